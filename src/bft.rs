@@ -0,0 +1,143 @@
+use super::codec;
+use super::crypto::{KeyPair, PublicKey, Signature};
+use super::peer;
+use super::{Block, BlockID, Peer};
+use serde::{Deserialize, Serialize};
+
+// Vote of a validator in the first phase of the BFT engine, for a block
+// proposed in the given round.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Prepare {
+  id: BlockID,
+  height: u64,
+  round: u64,
+  index: usize,
+  signature: Signature,
+}
+
+impl Prepare {
+  pub fn new(id: BlockID, height: u64, round: u64, index: usize, keypair: &KeyPair) -> Self {
+    let signature = keypair.sign(&Self::message(&id, height, round));
+
+    Prepare {
+      id,
+      height,
+      round,
+      index,
+      signature,
+    }
+  }
+
+  pub fn get_id(&self) -> &BlockID {
+    &self.id
+  }
+
+  pub fn get_height(&self) -> u64 {
+    self.height
+  }
+
+  pub fn get_round(&self) -> u64 {
+    self.round
+  }
+
+  pub fn get_index(&self) -> usize {
+    self.index
+  }
+
+  pub fn verify(&self, peers: &[Peer]) -> bool {
+    match peers.get(self.index) {
+      Some(peer) => {
+        let msg = Self::message(&self.id, self.height, self.round);
+        peer.get_public_key().verify(&msg, &self.signature)
+      }
+      None => false,
+    }
+  }
+
+  fn message(id: &BlockID, height: u64, round: u64) -> Vec<u8> {
+    codec::encode_unbounded(&("prepare", id, height, round))
+  }
+}
+
+// A quorum of prepare votes for the same block and round. Validators lock on
+// the block when they see it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PrepareCertificate {
+  prepares: Vec<Prepare>,
+}
+
+impl PrepareCertificate {
+  pub fn new(prepares: Vec<Prepare>) -> Self {
+    PrepareCertificate { prepares }
+  }
+
+  pub fn get_id(&self) -> Option<&BlockID> {
+    self.prepares.first().map(|p| p.get_id())
+  }
+
+  pub fn get_round(&self) -> Option<u64> {
+    self.prepares.first().map(|p| p.get_round())
+  }
+
+  pub fn verify(&self, peers: &[Peer], quorum: u64) -> bool {
+    let first = match self.prepares.first() {
+      Some(p) => p,
+      None => return false,
+    };
+
+    let mut seen = vec![false; peers.len()];
+    for p in &self.prepares {
+      let same = p.id == first.id && p.height == first.height && p.round == first.round;
+      if same && p.index < seen.len() && !seen[p.index] && p.verify(peers) {
+        seen[p.index] = true;
+      }
+    }
+
+    peer::power(peers, (0..seen.len()).filter(|&i| seen[i])) >= quorum
+  }
+}
+
+// Proposal of the leader of a round. The block is either a new one created for
+// the round, or a block proposed in a previous round that is justified by a
+// prepare certificate so that locked validators can accept it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Proposal {
+  round: u64,
+  block: Block,
+  justify: Option<PrepareCertificate>,
+  signature: Signature,
+}
+
+impl Proposal {
+  pub fn new(round: u64, block: Block, justify: Option<PrepareCertificate>, keypair: &KeyPair) -> Self {
+    let signature = keypair.sign(&Self::message(&block.hash(), round));
+
+    Proposal {
+      round,
+      block,
+      justify,
+      signature,
+    }
+  }
+
+  pub fn get_round(&self) -> u64 {
+    self.round
+  }
+
+  pub fn get_block(&self) -> &Block {
+    &self.block
+  }
+
+  pub fn get_justify(&self) -> Option<&PrepareCertificate> {
+    self.justify.as_ref()
+  }
+
+  // Checks that the proposal is signed by the given leader of the round.
+  pub fn verify(&self, public_key: &PublicKey) -> bool {
+    public_key.verify(&Self::message(&self.block.hash(), self.round), &self.signature)
+  }
+
+  fn message(id: &BlockID, round: u64) -> Vec<u8> {
+    codec::encode_unbounded(&("proposal", id, round))
+  }
+}
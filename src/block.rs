@@ -1,11 +1,23 @@
+use super::codec;
+use super::crypto::{KeyPair, PublicKey, Signature};
+use super::merkle::{self, MerkleProof};
+use super::peer;
+use super::evidence::{self, Evidence};
+use super::vrf;
+use super::{Genesis, Peer, Transaction, TxID};
 use ring::digest;
 use rand::prelude::{StdRng, SeedableRng};
 use serde::{Deserialize, Serialize};
+use std::io;
 use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 const ID_SHORT_LEN: usize = 4;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+// Milliseconds a block can be ahead of the clock of a node.
+const MAX_CLOCK_DRIFT: u64 = 10_000;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct BlockID([u8; 32]);
 
 impl std::fmt::Display for BlockID {
@@ -17,97 +29,499 @@ impl std::fmt::Display for BlockID {
   }
 }
 
-impl PartialEq for BlockID {
-  fn eq(&self, other: &Self) -> bool {
-    self.0 == other.0
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Seed([u8; 32]);
+
+impl Seed {
+  fn from_output(output: &vrf::Output) -> Self {
+    Seed(sha256(output))
   }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Hash)]
-pub struct Seed([u8; 32]);
+// Parses the hexadecimal form of the seed, as written in a genesis file.
+impl std::str::FromStr for Seed {
+  type Err = io::Error;
 
-impl PartialEq for Seed {
-  fn eq(&self, other: &Self) -> bool {
-    self.0 == other.0
+  fn from_str(s: &str) -> io::Result<Self> {
+    codec::from_hex(s)
+      .map(Seed)
+      .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("invalid seed {:?}", s)))
   }
 }
 
-impl Eq for Seed {}
+fn sha256(buf: &[u8]) -> [u8; 32] {
+  let d = digest::digest(&digest::SHA256, buf);
+  let mut res: [u8; digest::SHA256_OUTPUT_LEN] = Default::default();
+  res[..].clone_from_slice(d.as_ref());
+  res
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Block {
+pub struct Ack {
+  height: u64,
+  round: u64,
+  index: usize,
+  signature: Signature,
+}
+
+impl Ack {
+  // Acknowledges the block on behalf of the validator at the given index by
+  // signing its hash, its height and the round in which the block is
+  // accepted.
+  pub fn new(id: &BlockID, height: u64, round: u64, index: usize, keypair: &KeyPair) -> Self {
+    Ack {
+      height,
+      round,
+      index,
+      signature: keypair.sign(&Self::message(id, height, round)),
+    }
+  }
+
+  pub fn get_height(&self) -> u64 {
+    self.height
+  }
+
+  pub fn get_round(&self) -> u64 {
+    self.round
+  }
+
+  pub fn get_index(&self) -> usize {
+    self.index
+  }
+
+  pub fn verify(&self, id: &BlockID, peers: &[Peer]) -> bool {
+    match peers.get(self.index) {
+      Some(peer) => peer
+        .get_public_key()
+        .verify(&Self::message(id, self.height, self.round), &self.signature),
+      None => false,
+    }
+  }
+
+  fn message(id: &BlockID, height: u64, round: u64) -> Vec<u8> {
+    codec::encode_unbounded(&(id, height, round))
+  }
+}
+
+// A quorum certificate gathers the signatures of the validators over the hash
+// of a block so that anyone can later check who acknowledged it. Every ack of
+// a certificate belongs to the same height and round.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct QuorumCertificate {
+  acks: Vec<Ack>,
+}
+
+impl QuorumCertificate {
+  pub fn get_acks(&self) -> &[Ack] {
+    &self.acks
+  }
+
+  pub fn has_ack(&self, index: usize) -> bool {
+    self.acks.iter().any(|a| a.index == index)
+  }
+
+  pub fn add(&mut self, ack: Ack) {
+    let same_round = self
+      .acks
+      .first()
+      .is_none_or(|a| a.height == ack.height && a.round == ack.round);
+    if same_round && !self.has_ack(ack.index) {
+      self.acks.push(ack);
+    }
+  }
+
+  // Round of the acks, if there is any.
+  pub fn get_round(&self) -> Option<u64> {
+    self.acks.first().map(|a| a.round)
+  }
+
+  // Returns the voting power of the distinct validators that produced a valid
+  // signature for the given block at the given height and round. The acks
+  // signed for another height or round are not counted, otherwise a
+  // validator could acknowledge two blocks without being caught.
+  pub fn verify(&self, id: &BlockID, height: u64, round: u64, peers: &[Peer]) -> u64 {
+    let mut seen = vec![false; peers.len()];
+
+    for ack in &self.acks {
+      let valid = ack.height == height && ack.round == round && ack.index < seen.len() && !seen[ack.index];
+      if valid && ack.verify(id, peers) {
+        seen[ack.index] = true;
+      }
+    }
+
+    peer::power(peers, (0..seen.len()).filter(|&i| seen[i]))
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BlockHeader {
+  parent: BlockID,
+  height: u64,
+  round: u64,
+  timestamp: u64,
   leader: SocketAddr,
+  // VRF proof of the leader over the previous seed, which gives this seed.
+  beacon: Option<vrf::Proof>,
   seed: Seed,
-  ack: Vec<bool>,
-  data: Vec<u8>,
+  // Root of the Merkle tree over the encoded transactions of the block.
+  tx_root: merkle::Hash,
+  // Root of the Merkle tree over the encoded evidence of the block.
+  evidence_root: merkle::Hash,
+  // Root of the state of the application after the parent block.
+  state_root: merkle::Hash,
+}
+
+impl BlockHeader {
+  pub fn get_height(&self) -> u64 {
+    self.height
+  }
+
+  pub fn get_round(&self) -> u64 {
+    self.round
+  }
+
+  pub fn has_leader(&self, addr: &SocketAddr) -> bool {
+    self.leader == *addr
+  }
+
+  pub fn get_tx_root(&self) -> &merkle::Hash {
+    &self.tx_root
+  }
+
+  // The hash of the header is the ID of its block, as the header commits to
+  // the rest of the block with the roots.
+  pub fn hash(&self) -> BlockID {
+    BlockID(sha256(&codec::encode_unbounded(self)))
+  }
+
+  // Checks the signature of the block of this header by the given leader.
+  pub fn verify(&self, public_key: &PublicKey, signature: &Signature) -> bool {
+    public_key.verify(&self.hash().0, signature)
+  }
+
+  // Checks the proof that the transaction belongs to the block of this
+  // header, without the other transactions of the block.
+  pub fn has_tx(&self, tx: &Transaction, proof: &MerkleProof) -> bool {
+    proof.verify(&codec::encode_unbounded(tx), &self.tx_root)
+  }
+}
+
+fn tx_root(txs: &[Transaction]) -> merkle::Hash {
+  merkle::root(&leaves(txs))
+}
+
+fn leaves(txs: &[Transaction]) -> Vec<Vec<u8>> {
+  txs.iter().map(codec::encode_unbounded).collect()
+}
+
+fn evidence_root(evidence: &[Evidence]) -> merkle::Hash {
+  merkle::root(&evidence.iter().map(codec::encode_unbounded).collect::<Vec<_>>())
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Block {
+  header: BlockHeader,
+  qc: QuorumCertificate,
+  txs: Vec<Transaction>,
+  // Faults of validators reported by the leader, which are then excluded
+  // from the leader schedule.
+  evidence: Vec<Evidence>,
+  signature: Option<Signature>,
 }
 
 impl Block {
-  pub fn new(leader: SocketAddr, data: Vec<u8>) -> Self {
+  // Creates the first block of the chain described by the genesis. Its parent
+  // is the hash of the genesis document so that its ID commits to the chain
+  // ID, the validators and the initial state. Every node must agree on the
+  // genesis seed so the unpredictability only starts with the beacon of the
+  // first block.
+  pub fn genesis(genesis: &Genesis) -> Self {
     Block {
-      leader,
-      seed: Seed(Default::default()), // TODO: random
-      ack: Vec::new(),
-      data,
+      header: BlockHeader {
+        parent: BlockID(sha256(&codec::encode_unbounded(genesis))),
+        height: 0,
+        round: 0,
+        timestamp: 0,
+        leader: ([0, 0, 0, 0], 0).into(),
+        beacon: None,
+        seed: genesis.get_seed().clone(),
+        tx_root: tx_root(&[]),
+        evidence_root: evidence_root(&[]),
+        state_root: Default::default(),
+      },
+      qc: Default::default(),
+      txs: Vec::new(),
+      evidence: Vec::new(),
+      signature: None,
     }
   }
 
+  pub fn get_header(&self) -> &BlockHeader {
+    &self.header
+  }
+
+  pub fn get_parent(&self) -> &BlockID {
+    &self.header.parent
+  }
+
+  pub fn get_height(&self) -> u64 {
+    self.header.height
+  }
+
+  pub fn get_round(&self) -> u64 {
+    self.header.round
+  }
+
+  pub fn get_timestamp(&self) -> u64 {
+    self.header.timestamp
+  }
+
   pub fn get_seed(&self) -> &Seed {
-    &self.seed
+    &self.header.seed
+  }
+
+  pub fn get_state_root(&self) -> &merkle::Hash {
+    &self.header.state_root
+  }
+
+  // The root is covered by the hash so it must be set before signing.
+  pub fn set_state_root(&mut self, root: merkle::Hash) {
+    self.header.state_root = root;
   }
 
   pub fn get_rng(&self) -> StdRng {
-    StdRng::from_seed(self.seed.0)
+    StdRng::from_seed(self.header.seed.0)
   }
 
-  pub fn get_ack(&self) -> usize {
-    self.ack.iter().filter(|&v| *v).count()
+  pub fn get_txs(&self) -> &[Transaction] {
+    &self.txs
   }
 
-  pub fn incr_ack(&mut self, index: usize) {
-    if self.ack.len() < index + 1 {
-      self.ack.resize(index + 1, false);
+  // Returns the proof that the transaction belongs to the block, if it does.
+  pub fn prove(&self, id: &TxID) -> Option<MerkleProof> {
+    let index = self.txs.iter().position(|tx| tx.hash() == *id)?;
+    merkle::prove(&leaves(&self.txs), index)
+  }
+
+  // Returns the proofs of every transaction in order.
+  pub fn prove_all(&self) -> Vec<MerkleProof> {
+    merkle::prove_all(&leaves(&self.txs))
+  }
+
+  pub fn get_qc(&self) -> &QuorumCertificate {
+    &self.qc
+  }
+
+  pub fn ack(&mut self, index: usize, keypair: &KeyPair) {
+    let ack = Ack::new(&self.hash(), self.header.height, self.header.round, index, keypair);
+    self.qc.add(ack);
+  }
+
+  pub fn set_qc(&mut self, qc: QuorumCertificate) {
+    self.qc = qc;
+  }
+
+  // Adds the acknowledgement of a validator which is expected to be verified.
+  pub fn add_ack(&mut self, ack: Ack) {
+    self.qc.add(ack);
+  }
+
+  // Returns the voting power of the valid acknowledgements for the given
+  // validators, which are signed in the round of the block.
+  pub fn verify_acks(&self, peers: &[Peer]) -> u64 {
+    self.qc.verify(&self.hash(), self.header.height, self.header.round, peers)
+  }
+
+  // Returns the voting power of the valid commit votes for the given
+  // validators. They are signed in the round the block is proposed in, which
+  // is later than the round of the block when it has been proposed again.
+  pub fn verify_commits(&self, peers: &[Peer]) -> u64 {
+    match self.qc.get_round() {
+      Some(round) if round >= self.header.round => self.qc.verify(&self.hash(), self.header.height, round, peers),
+      _ => 0,
     }
+  }
 
-    self.ack[index] = true;
+  pub fn get_evidence(&self) -> &[Evidence] {
+    &self.evidence
   }
 
-  pub fn verify(&self) -> bool {
-    return true;
+  // The evidence is covered by the hash so it must be set before signing.
+  pub fn set_evidence(&mut self, evidence: Vec<Evidence>) {
+    self.header.evidence_root = evidence_root(&evidence);
+    self.evidence = evidence;
   }
 
-  pub fn has_next(&self, block: &Self) -> bool {
-    self.generate_next_seed() == *block.get_seed()
+  pub fn verify_evidence(&self, peers: &[Peer]) -> bool {
+    self.evidence.len() <= evidence::MAX_PER_BLOCK && self.evidence.iter().all(|e| e.verify(peers))
   }
 
-  pub fn has_leader(&self, addr: &SocketAddr) -> bool {
-    self.leader == *addr
+  pub fn get_leader(&self) -> &SocketAddr {
+    &self.header.leader
   }
 
-  pub fn hash(&self) -> BlockID {
-    let mut c = digest::Context::new(&digest::SHA256);
-    c.update(&self.data);
-    c.update(&self.seed.0);
+  pub fn sign(&mut self, keypair: &KeyPair) {
+    self.signature = Some(keypair.sign(&self.hash().0));
+  }
+
+  pub fn get_signature(&self) -> Option<&Signature> {
+    self.signature.as_ref()
+  }
 
-    let mut id: [u8; digest::SHA256_OUTPUT_LEN] = Default::default();
-    id[..].clone_from_slice(c.finish().as_ref());
+  // Checks that the block has been signed by the leader which is expected to
+  // own the given public key, that the transactions and the evidence match
+  // the roots of the header and that every transaction has been signed by
+  // its sender.
+  pub fn verify(&self, public_key: &PublicKey) -> bool {
+    let signed = match &self.signature {
+      Some(sig) => self.header.verify(public_key, sig),
+      None => false,
+    };
 
-    BlockID(id)
+    signed
+      && tx_root(&self.txs) == self.header.tx_root
+      && evidence_root(&self.evidence) == self.header.evidence_root
+      && self.txs.iter().all(|tx| tx.verify())
   }
 
-  pub fn next(&self, leader: SocketAddr, data: Vec<u8>) -> Self {
+  // Checks that the block is the child of this one. The public key of the
+  // leader of the block is required to check the randomness beacon. The time
+  // of the block can't go back, nor be ahead of the clock of this node by
+  // more than the allowed drift.
+  pub fn has_next(&self, block: &Self, public_key: &PublicKey) -> bool {
+    let beacon = match &block.header.beacon {
+      Some(beacon) => beacon,
+      None => return false,
+    };
+
+    *block.get_parent() == self.hash()
+      && block.get_height() == self.header.height + 1
+      && block.get_timestamp() >= self.header.timestamp
+      && block.get_timestamp() <= now().saturating_add(MAX_CLOCK_DRIFT)
+      && public_key
+        .verify_proof(&self.beacon_message(), beacon)
+        .is_some_and(|output| Seed::from_output(&output) == *block.get_seed())
+  }
+
+  pub fn has_leader(&self, addr: &SocketAddr) -> bool {
+    self.header.leader == *addr
+  }
+
+  // The hash covers the canonical encoding of the header, which commits to
+  // the transactions and the evidence with their roots. The signatures are
+  // left out as they are computed over the hash.
+  pub fn hash(&self) -> BlockID {
+    self.header.hash()
+  }
+
+  // Creates the child of this block. The leader contributes the randomness of
+  // the new seed with its VRF proof over the current one, which nobody can
+  // predict without its key. The proof is unique so the leader can't try
+  // several ones to pick the seed, which orders the next leaders.
+  pub fn next(&self, keypair: &KeyPair, leader: SocketAddr, round: u64, txs: Vec<Transaction>) -> Self {
+    let beacon = keypair.prove(&self.beacon_message());
+
     Block {
-      leader,
-      seed: self.generate_next_seed(),
-      ack: Vec::new(),
-      data,
+      header: BlockHeader {
+        parent: self.hash(),
+        height: self.header.height + 1,
+        round,
+        timestamp: now().max(self.header.timestamp),
+        leader,
+        seed: Seed::from_output(&vrf::proof_to_hash(&beacon).unwrap()),
+        beacon: Some(beacon),
+        tx_root: tx_root(&txs),
+        evidence_root: evidence_root(&[]),
+        state_root: Default::default(),
+      },
+      qc: Default::default(),
+      txs,
+      evidence: Vec::new(),
+      signature: None,
     }
   }
 
-  fn generate_next_seed(&self) -> Seed {
-    let d = digest::digest(&digest::SHA256, &self.seed.0[..]);
-    let mut id: [u8; digest::SHA256_OUTPUT_LEN] = Default::default();
-    id[..].clone_from_slice(d.as_ref());
-    Seed(id)
+  fn beacon_message(&self) -> Vec<u8> {
+    codec::encode_unbounded(&(&self.header.seed, self.header.height + 1))
+  }
+}
+
+// Milliseconds elapsed since the UNIX epoch.
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_millis() as u64)
+    .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn validators(n: u16) -> (Vec<KeyPair>, Vec<Peer>) {
+    let keys: Vec<_> = (0..n).map(|_| KeyPair::generate().unwrap()).collect();
+    let peers = (0..n)
+      .map(|i| Peer::new(SocketAddr::from(([127, 0, 0, 1], 3000 + i)), keys[i as usize].public_key(), 1))
+      .collect();
+    (keys, peers)
+  }
+
+  fn first_block(keys: &[KeyPair], peers: &[Peer], round: u64) -> Block {
+    let genesis = Genesis::new("test".to_string(), peers.to_vec(), Seed::default(), Vec::new());
+    Block::genesis(&genesis).next(&keys[0], *peers[0].get_addr(), round, Vec::new())
+  }
+
+  #[test]
+  fn counts_acks_of_the_round_of_the_block() {
+    let (keys, peers) = validators(3);
+    let mut block = first_block(&keys, &peers, 2);
+    block.ack(0, &keys[0]);
+    block.ack(1, &keys[1]);
+    assert_eq!(block.verify_acks(&peers), 2);
+
+    // An ack signed for another height or round doesn't count.
+    let id = block.hash();
+    let mut qc = QuorumCertificate::default();
+    qc.add(Ack::new(&id, 999, 777, 0, &keys[0]));
+    qc.add(Ack::new(&id, 999, 777, 1, &keys[1]));
+    block.set_qc(qc);
+    assert_eq!(block.verify_acks(&peers), 0);
+    assert_eq!(block.verify_commits(&peers), 0);
+  }
+
+  #[test]
+  fn counts_commits_of_a_later_round() {
+    let (keys, peers) = validators(3);
+    let mut block = first_block(&keys, &peers, 2);
+    let id = block.hash();
+
+    let mut qc = QuorumCertificate::default();
+    qc.add(Ack::new(&id, 1, 3, 0, &keys[0]));
+    qc.add(Ack::new(&id, 1, 3, 1, &keys[1]));
+    block.set_qc(qc);
+    assert_eq!(block.verify_commits(&peers), 2);
+    assert_eq!(block.verify_acks(&peers), 0);
+
+    // The block can't be committed before the round it was created in.
+    let mut qc = QuorumCertificate::default();
+    qc.add(Ack::new(&id, 1, 1, 0, &keys[0]));
+    block.set_qc(qc);
+    assert_eq!(block.verify_commits(&peers), 0);
+  }
+  #[test]
+  fn checks_the_time_of_the_next_block() {
+    let (keys, peers) = validators(1);
+    let parent = first_block(&keys, &peers, 0);
+    let public_key = keys[0].public_key();
+
+    let mut block = parent.next(&keys[0], *peers[0].get_addr(), 0, Vec::new());
+    assert!(parent.has_next(&block, &public_key));
+
+    block.header.timestamp = parent.get_timestamp() - 1;
+    assert!(!parent.has_next(&block, &public_key));
+    block.header.timestamp = now() + MAX_CLOCK_DRIFT / 2;
+    assert!(parent.has_next(&block, &public_key));
+    block.header.timestamp = now() + 2 * MAX_CLOCK_DRIFT;
+    assert!(!parent.has_next(&block, &public_key));
   }
 }
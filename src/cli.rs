@@ -0,0 +1,147 @@
+use super::client::Client;
+use super::codec;
+use super::config::{with_path, Config};
+use super::crypto::KeyPair;
+use super::server::{Server, Store};
+use super::{Block, KvOp, KvStore, Transaction};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const USAGE: &str = "usage: rust-ds <command> [<args>]
+
+commands:
+  keygen <key-file>              write a new key and print its public key
+  node <config-file>             run a node
+  submit <node> <key-file> <nonce> set <key> <value>
+  submit <node> <key-file> <nonce> delete <key>
+                                 submit a transaction and wait for its receipt
+  tip <node>                     print the last block of a node
+  block <node> <height>          print the block of a node at the height
+  inspect <block-log>            print the blocks of a local block log
+  demo [ack|bft|raft]            run a local cluster of 5 nodes";
+
+// Time given to a transaction to be committed.
+const RECEIPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn parse<T: FromStr>(s: &str, what: &str) -> io::Result<T> {
+  s.parse()
+    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid {} {:?}", what, s)))
+}
+
+// Writes a new key to the file, which must not exist yet, and prints the
+// public key to put in the genesis of the network.
+pub fn keygen(path: &Path) -> io::Result<()> {
+  let pkcs8 = KeyPair::generate_pkcs8()?;
+  fs::OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .open(path)
+    .and_then(|mut file| io::Write::write_all(&mut file, &pkcs8))
+    .map_err(|e| with_path(path, e))?;
+
+  println!("{}", KeyPair::from_pkcs8(&pkcs8)?.public_key());
+  Ok(())
+}
+
+// Runs a node of the key/value store until the process is killed.
+pub fn node(path: &Path) -> io::Result<()> {
+  let config = Config::load(path)?;
+  let genesis = config.load_genesis()?;
+  let keypair = config.load_keypair(&genesis)?;
+
+  let mut srv = Server::new(config.get_listen(), keypair, &genesis, KvStore::new(), config.get_options())?;
+  srv.start();
+  srv.wait(|_| false);
+  Ok(())
+}
+
+// Submits an operation of the key/value store signed with the key, and
+// waits for the transaction to be committed.
+pub fn submit(node: &str, key: &str, nonce: &str, op: &[&str]) -> io::Result<()> {
+  let addr: SocketAddr = parse(node, "node address")?;
+  let nonce = parse(nonce, "nonce")?;
+  let op = match op {
+    ["set", key, value] => KvOp::Set(key.as_bytes().to_vec(), value.as_bytes().to_vec()),
+    ["delete", key] => KvOp::Delete(key.as_bytes().to_vec()),
+    _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "expected set <key> <value> or delete <key>")),
+  };
+
+  let key = Path::new(key);
+  let bytes = fs::read(key).map_err(|e| with_path(key, e))?;
+  let keypair = KeyPair::from_pkcs8(&bytes).map_err(|e| with_path(key, e))?;
+  let tx = Transaction::new(nonce, codec::encode_unbounded(&op), &keypair);
+
+  let cl = Client::new()?;
+  let id = cl.submit(&addr, tx.clone())?;
+  let receipt = cl.wait_receipt(&id, RECEIPT_TIMEOUT)?;
+
+  // The proof is checked against the header of the block.
+  let valid = cl
+    .get_block_by_id(&addr, receipt.get_block())?
+    .is_some_and(|block| block.get_header().has_tx(&tx, receipt.get_proof()));
+  println!(
+    "transaction {} committed in block {} at height {} with a valid proof: {}",
+    id,
+    receipt.get_block(),
+    receipt.get_height(),
+    valid
+  );
+  Ok(())
+}
+
+pub fn tip(node: &str) -> io::Result<()> {
+  let cl = Client::new()?;
+  print_block(&cl.get_tip(&parse(node, "node address")?)?);
+  Ok(())
+}
+
+pub fn block(node: &str, height: &str) -> io::Result<()> {
+  let cl = Client::new()?;
+  match cl.get_block(&parse(node, "node address")?, parse(height, "height")?)? {
+    Some(block) => print_block(&block),
+    None => println!("no block at height {}", height),
+  };
+  Ok(())
+}
+
+// Prints the blocks of a block log in the order they have been written,
+// which includes the blocks of every branch the node has seen.
+pub fn inspect(path: &Path) -> io::Result<()> {
+  let (blocks, corrupted) = Store::read(path).map_err(|e| with_path(path, e))?;
+  for block in &blocks {
+    print_block(block);
+  }
+
+  println!("{} blocks", blocks.len());
+  if corrupted > 0 {
+    println!("{} bytes of corrupted tail", corrupted);
+  }
+  Ok(())
+}
+
+fn print_block(block: &Block) {
+  println!("block {} at height {}", block.hash(), block.get_height());
+  println!("  parent: {}", block.get_parent());
+  println!("  round: {}", block.get_round());
+  println!("  leader: {}", block.get_leader());
+  println!("  timestamp: {}", block.get_timestamp());
+  println!("  state root: {}", hex(block.get_state_root()));
+  println!("  transactions: {}", block.get_txs().len());
+
+  for tx in block.get_txs() {
+    let op = match codec::decode(tx.get_payload()) {
+      Ok(KvOp::Set(key, value)) => format!("set {:?} to {:?}", String::from_utf8_lossy(&key), String::from_utf8_lossy(&value)),
+      Ok(KvOp::Delete(key)) => format!("delete {:?}", String::from_utf8_lossy(&key)),
+      Err(_) => format!("{} bytes", tx.get_payload().len()),
+    };
+    println!("    {} from {} with nonce {}: {}", tx.hash(), tx.get_sender(), tx.get_nonce(), op);
+  }
+}
+
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
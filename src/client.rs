@@ -1,6 +1,11 @@
 use std::net::{UdpSocket, SocketAddr};
 use std::io;
-use super::Message;
+use std::time::{Duration, Instant};
+use super::codec;
+use super::{Block, BlockID, ChainQuery, Message, Receipt, Transaction, TxID};
+
+// Time given to a node to answer.
+const TIMEOUT: Duration = Duration::from_secs(1);
 
 pub struct Client {
   socket: UdpSocket,
@@ -9,11 +14,96 @@ pub struct Client {
 impl Client {
   pub fn new() -> io::Result<Self> {
     let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
     Ok(Client{ socket })
   }
 
-  pub fn send_to(&self, addr: &SocketAddr, msg: Message) {
-    let buf = serde_json::to_vec(&msg).unwrap();
-    self.socket.send_to(&buf, addr).unwrap();
+  // Sends the message, which fails when it doesn't fit in a datagram.
+  pub fn send_to(&self, addr: &SocketAddr, msg: Message) -> io::Result<()> {
+    let buf = codec::encode(&msg)?;
+    self.socket.send_to(&buf, addr)?;
+    Ok(())
+  }
+
+  // Sends a query to the application of the node and returns the answer with
+  // the block whose header holds the root of the queried state.
+  pub fn query(&self, addr: &SocketAddr, data: Vec<u8>) -> io::Result<(BlockID, Vec<u8>)> {
+    self.send_to(addr, Message::Query(data))?;
+
+    self.receive_until(TIMEOUT, |msg| match msg {
+      Message::QueryResult(id, res) => Some((id, res)),
+      _ => None,
+    })
+  }
+
+  pub fn get_tip(&self, addr: &SocketAddr) -> io::Result<Block> {
+    let mut blocks = self.query_chain(addr, ChainQuery::Tip)?;
+    blocks.pop().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing tip"))
+  }
+
+  // Returns the block of the best branch of the node at the given height.
+  pub fn get_block(&self, addr: &SocketAddr, height: u64) -> io::Result<Option<Block>> {
+    Ok(self.query_chain(addr, ChainQuery::Height(height))?.pop())
+  }
+
+  pub fn get_block_by_id(&self, addr: &SocketAddr, id: &BlockID) -> io::Result<Option<Block>> {
+    Ok(self.query_chain(addr, ChainQuery::Block(id.clone()))?.pop())
+  }
+
+  // Returns a page of at most the given number of blocks of the best branch
+  // starting at the given height. A page can be shorter than asked, and the
+  // chain has been read up to the tip when it is empty.
+  pub fn get_blocks(&self, addr: &SocketAddr, start: u64, limit: u64) -> io::Result<Vec<Block>> {
+    self.query_chain(addr, ChainQuery::Range(start, limit))
+  }
+
+  fn query_chain(&self, addr: &SocketAddr, query: ChainQuery) -> io::Result<Vec<Block>> {
+    self.send_to(addr, Message::GetChain(query))?;
+
+    self.receive_until(TIMEOUT, |msg| match msg {
+      Message::ChainResult(blocks) => Some(blocks),
+      _ => None,
+    })
+  }
+
+  // Sends the transaction to the node, which will send back a receipt once it
+  // is committed, and returns its ID.
+  pub fn submit(&self, addr: &SocketAddr, tx: Transaction) -> io::Result<TxID> {
+    let id = tx.hash();
+    self.send_to(addr, Message::Request(tx))?;
+    Ok(id)
+  }
+
+  // Waits for the receipt of a submitted transaction.
+  pub fn wait_receipt(&self, id: &TxID, timeout: Duration) -> io::Result<Receipt> {
+    self.receive_until(timeout, |msg| match msg {
+      Message::Receipt(receipt) if receipt.get_tx() == id => Some(receipt),
+      _ => None,
+    })
+  }
+
+  // Receives messages until one is accepted before the timeout. The others
+  // are dropped, which can be late answers to previous requests.
+  fn receive_until<T>(&self, timeout: Duration, mut accept: impl FnMut(Message) -> Option<T>) -> io::Result<T> {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+      match self.receive() {
+        Ok(msg) => {
+          if let Some(res) = accept(msg) {
+            return Ok(res);
+          }
+        }
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => (),
+        Err(e) => return Err(e),
+      }
+    }
+
+    Err(io::Error::new(io::ErrorKind::TimedOut, "no answer"))
+  }
+
+  fn receive(&self) -> io::Result<Message> {
+    let mut buf = vec![0; codec::MAX_SIZE as usize];
+    let (size, _) = self.socket.recv_from(&mut buf)?;
+    codec::decode(&buf[..size])
   }
 }
@@ -0,0 +1,176 @@
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io;
+
+// Every encoded value starts with this tag so that the format can evolve
+// without old nodes misreading new messages.
+pub const VERSION: u8 = 1;
+
+// Upper bound of an encoded value, which is the largest UDP payload.
+pub const MAX_SIZE: u64 = 65_507;
+
+// Integers are encoded with a fixed size in little endian, sequences and
+// strings are prefixed by their length as a u64 and enums by their variant
+// index as a u32. The same encoding is used on the wire and for hashing.
+fn unbounded() -> impl Options {
+  bincode::DefaultOptions::new()
+    .with_fixint_encoding()
+    .with_little_endian()
+}
+
+fn options() -> impl Options {
+  unbounded().with_limit(MAX_SIZE)
+}
+
+// Encodes a value sent on the wire or written to the block log, which fails
+// when it doesn't fit in a datagram.
+pub fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+  let mut buf = vec![VERSION];
+  options()
+    .serialize_into(&mut buf, value)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+  Ok(buf)
+}
+
+// Encodes a value to hash or sign it, or to measure it. The bytes are the
+// same as with encode but they are never sent so there is no size limit.
+pub fn encode_unbounded<T: Serialize>(value: &T) -> Vec<u8> {
+  let mut buf = vec![VERSION];
+  unbounded()
+    .serialize_into(&mut buf, value)
+    .expect("value can't be encoded");
+  buf
+}
+
+pub fn decode<T: DeserializeOwned>(buf: &[u8]) -> io::Result<T> {
+  decode_with(options(), buf)
+}
+
+// Decodes a value read from a local file, such as the genesis state, which
+// may not fit in a datagram.
+pub fn decode_unbounded<T: DeserializeOwned>(buf: &[u8]) -> io::Result<T> {
+  decode_with(unbounded(), buf)
+}
+
+fn decode_with<T: DeserializeOwned>(options: impl Options, buf: &[u8]) -> io::Result<T> {
+  match buf.split_first() {
+    Some((&VERSION, rest)) => options
+      .deserialize(rest)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    Some((v, _)) => Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!("unsupported encoding version {}", v),
+    )),
+    None => Err(io::Error::new(io::ErrorKind::InvalidData, "empty buffer")),
+  }
+}
+
+// Parses 32 bytes written as 64 hexadecimal digits.
+pub fn from_hex(s: &str) -> Option<[u8; 32]> {
+  if s.len() != 64 || !s.is_ascii() {
+    return None;
+  }
+
+  let mut bytes: [u8; 32] = Default::default();
+  for (i, b) in bytes.iter_mut().enumerate() {
+    *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+  }
+  Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::crypto::KeyPair;
+  use crate::{Block, BlockID, BlockRequest, Event, Genesis, Hello, Message, Seed};
+
+  fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+  }
+
+  fn genesis_block() -> Block {
+    Block::genesis(&Genesis::new("test".to_string(), Vec::new(), Seed::default(), Vec::new()))
+  }
+
+  #[test]
+  fn encodes_block() {
+    let expected = [
+      "01",
+      // Header: parent, which is the hash of the genesis document, height,
+      // round and timestamp.
+      "cce8dbbace679d9fec18de8857d18bc7d05e6941587af18971333691443fb6d7",
+      "0000000000000000",
+      "0000000000000000",
+      "0000000000000000",
+      // Leader as the V4 variant with its IP and port, and no beacon.
+      "00000000",
+      "00000000",
+      "0000",
+      "00",
+      // Seed, roots of no transactions and no evidence, and state root.
+      "0000000000000000000000000000000000000000000000000000000000000000",
+      "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+      "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+      "0000000000000000000000000000000000000000000000000000000000000000",
+      // No ack, transaction or evidence, and no signature.
+      "0000000000000000",
+      "0000000000000000",
+      "0000000000000000",
+      "00",
+    ];
+
+    assert_eq!(hex(&encode(&genesis_block()).unwrap()), expected.concat());
+  }
+
+  #[test]
+  fn encodes_event() {
+    let evt = Event::GetBlocks(BlockRequest::Height(7));
+
+    // Variants of the event and of the request, then the height.
+    let expected = ["01", "0c000000", "00000000", "0700000000000000"];
+    assert_eq!(hex(&encode(&evt).unwrap()), expected.concat());
+  }
+
+  #[test]
+  fn encodes_message() {
+    let msg = Message::Hello(Hello::new(BlockID::default(), true, &KeyPair::generate().unwrap()));
+
+    // The signature follows with its length.
+    let expected = [
+      "01",
+      "07000000",
+      "0000000000000000000000000000000000000000000000000000000000000000",
+      "01",
+      "4000000000000000",
+    ];
+    let buf = hex(&encode(&msg).unwrap());
+    assert!(buf.starts_with(&expected.concat()));
+    assert_eq!(buf.len(), expected.concat().len() + 2 * 64);
+  }
+
+  #[test]
+  fn decodes_what_it_encodes() {
+    let buf = encode(&genesis_block()).unwrap();
+    let block: Block = decode(&buf).unwrap();
+    assert_eq!(block.hash(), genesis_block().hash());
+  }
+
+  #[test]
+  fn rejects_unknown_version() {
+    let mut buf = encode(&Event::GetBlocks(BlockRequest::Height(7))).unwrap();
+    buf[0] = VERSION + 1;
+
+    let err = decode::<Event>(&buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(decode::<Event>(&[]).is_err());
+  }
+
+  #[test]
+  fn rejects_values_larger_than_a_datagram() {
+    let data = vec![0; MAX_SIZE as usize];
+
+    assert!(encode(&Message::Query(data.clone())).is_err());
+    assert!(encode_unbounded(&Message::Query(data)).len() > MAX_SIZE as usize);
+  }
+}
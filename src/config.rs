@@ -0,0 +1,242 @@
+use super::crypto::KeyPair;
+use super::server::{BlockPolicy, Consensus, Limits, Options, Timeouts};
+use super::Genesis;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Name of the block log in the data directory.
+const BLOCK_LOG: &str = "blocks.log";
+
+// Configuration of a node read from a TOML file such as:
+//
+//   listen = "127.0.0.1:3000"
+//   key = "node0.key"
+//   consensus = "bft"
+//   data_dir = "node0"
+//   genesis = "genesis.toml"
+//
+//   [timeouts]
+//   round = 2000
+//
+//   [limits]
+//   block_txs = 500
+//
+// Relative paths are resolved from the directory of the file. The timeouts
+// are in milliseconds, and the ones left out of the file, like the limits,
+// take the default value of the server.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+  listen: SocketAddr,
+  // File of the PKCS#8 key of the node.
+  key: PathBuf,
+  #[serde(default)]
+  consensus: Consensus,
+  // Directory of the block log and of the votes of the node. The chain only
+  // lives in memory when it is not set.
+  data_dir: Option<PathBuf>,
+  // File of the genesis of the network, which defines the validators.
+  genesis: PathBuf,
+  #[serde(default)]
+  timeouts: TimeoutsConfig,
+  #[serde(default)]
+  limits: LimitsConfig,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct TimeoutsConfig {
+  poll: Option<u64>,
+  round: Option<u64>,
+  block_interval: Option<u64>,
+  heartbeat: Option<u64>,
+  election_min: Option<u64>,
+  election_max: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct LimitsConfig {
+  quorum: Option<u64>,
+  block_txs: Option<usize>,
+  block_bytes: Option<usize>,
+  poll_events: Option<usize>,
+  mempool_txs: Option<usize>,
+  mempool_bytes: Option<usize>,
+}
+
+impl Config {
+  // Reads the file and checks that the node can start with it.
+  pub fn load(path: &Path) -> io::Result<Self> {
+    let content = fs::read_to_string(path).map_err(|e| with_path(path, e))?;
+    let mut config: Config = toml::from_str(&content)
+      .map_err(|e| with_path(path, io::Error::new(io::ErrorKind::InvalidData, e)))?;
+
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    config.key = dir.join(&config.key);
+    config.data_dir = config.data_dir.map(|d| dir.join(d));
+    config.genesis = dir.join(&config.genesis);
+
+    let genesis = config.load_genesis()?;
+    config
+      .get_options()
+      .validate(&genesis)
+      .map_err(|e| with_path(path, e))?;
+    Ok(config)
+  }
+
+  pub fn get_listen(&self) -> &SocketAddr {
+    &self.listen
+  }
+
+  // Reads the genesis, in which the node must be one of the validators.
+  pub fn load_genesis(&self) -> io::Result<Genesis> {
+    let genesis = Genesis::load(&self.genesis)?;
+    if genesis.get_validator(&self.listen).is_none() {
+      return Err(with_path(
+        &self.genesis,
+        io::Error::new(
+          io::ErrorKind::InvalidData,
+          format!("listen address {} is not one of the validators", self.listen),
+        ),
+      ));
+    }
+
+    Ok(genesis)
+  }
+
+  // Reads the key of the node, which must be the one of its validator.
+  pub fn load_keypair(&self, genesis: &Genesis) -> io::Result<KeyPair> {
+    let bytes = fs::read(&self.key).map_err(|e| with_path(&self.key, e))?;
+    let keypair = KeyPair::from_pkcs8(&bytes).map_err(|e| with_path(&self.key, e))?;
+
+    let validator = genesis.get_validator(&self.listen).unwrap();
+    if keypair.public_key() != *validator.get_public_key() {
+      return Err(with_path(
+        &self.key,
+        io::Error::new(
+          io::ErrorKind::InvalidData,
+          format!("key doesn't match the public key of validator {}", self.listen),
+        ),
+      ));
+    }
+
+    Ok(keypair)
+  }
+
+  pub fn get_options(&self) -> Options {
+    let ms = |value: Option<u64>, default: Duration| value.map_or(default, Duration::from_millis);
+    let (timeouts, limits) = (&self.timeouts, &self.limits);
+
+    let policy = BlockPolicy::default();
+    let policy = BlockPolicy {
+      max_txs: limits.block_txs.unwrap_or(policy.max_txs),
+      max_bytes: limits.block_bytes.unwrap_or(policy.max_bytes),
+      interval: ms(timeouts.block_interval, policy.interval),
+    };
+
+    let default = Timeouts::default();
+    let (min, max) = default.election;
+    let timeouts = Timeouts {
+      poll: ms(timeouts.poll, default.poll),
+      round: ms(timeouts.round, default.round),
+      heartbeat: ms(timeouts.heartbeat, default.heartbeat),
+      election: (ms(timeouts.election_min, min), ms(timeouts.election_max, max)),
+    };
+
+    let default = Limits::default();
+    let quorum = limits.quorum;
+    let limits = Limits {
+      poll_events: limits.poll_events.unwrap_or(default.poll_events),
+      mempool_txs: limits.mempool_txs.unwrap_or(default.mempool_txs),
+      mempool_bytes: limits.mempool_bytes.unwrap_or(default.mempool_bytes),
+    };
+
+    Options {
+      quorum,
+      consensus: self.consensus,
+      path: self.data_dir.as_ref().map(|dir| dir.join(BLOCK_LOG)),
+      policy,
+      timeouts,
+      limits,
+    }
+  }
+}
+
+// Adds the path to the message of an error about the file.
+pub fn with_path(path: &Path, e: io::Error) -> io::Error {
+  io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Writes a network of 4 validators in an empty directory of the temporary
+  // directory, and returns the path of the configuration of the first node
+  // with the given extra lines.
+  fn write_config(name: &str, extra: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rust-ds-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("node0")).unwrap();
+
+    let mut genesis = "chain_id = \"test\"\nseed = \"".to_string() + &"00".repeat(32) + "\"\n";
+    for port in 3000..3004 {
+      let public_key = KeyPair::generate().unwrap().public_key();
+      genesis += &format!("[[validators]]\naddr = \"127.0.0.1:{}\"\npublic_key = \"{}\"\n", port, public_key);
+    }
+    fs::write(dir.join("genesis.toml"), genesis).unwrap();
+
+    let config = "listen = \"127.0.0.1:3000\"\nkey = \"key\"\ndata_dir = \"data\"\ngenesis = \"../genesis.toml\"\n";
+    let path = dir.join("node0").join("node.toml");
+    fs::write(&path, config.to_string() + extra).unwrap();
+    path
+  }
+
+  fn load_error(name: &str, extra: &str) -> io::Error {
+    Config::load(&write_config(name, extra)).unwrap_err()
+  }
+
+  #[test]
+  fn resolves_paths_from_the_directory_of_the_file() {
+    let path = write_config("paths", "");
+    let dir = path.parent().unwrap();
+    let config = Config::load(&path).unwrap();
+
+    assert_eq!(config.key, dir.join("key"));
+    assert_eq!(config.genesis, dir.join("../genesis.toml"));
+    assert_eq!(config.get_options().path, Some(dir.join("data").join(BLOCK_LOG)));
+    assert_eq!(config.load_genesis().unwrap().get_validators().len(), 4);
+  }
+
+  #[test]
+  fn rejects_unknown_fields() {
+    let err = load_error("unknown", "port = 3000\n");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("port"), "{}", err);
+
+    let err = load_error("unknown-timeout", "[timeouts]\nrounds = 2000\n");
+    assert!(err.to_string().contains("rounds"), "{}", err);
+  }
+
+  #[test]
+  fn rejects_invalid_options() {
+    let err = load_error("election", "[timeouts]\nelection_min = 1000\nelection_max = 1000\n");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("election timeout"), "{}", err);
+
+    let err = load_error("mempool", "[limits]\nblock_txs = 500\nmempool_txs = 100\n");
+    assert!(err.to_string().contains("mempool must hold at least a full block"), "{}", err);
+
+    // Two quorums of 2 validators out of 4 may not overlap.
+    let err = load_error("quorum", "[limits]\nquorum = 2\n");
+    assert!(err.to_string().contains("quorum must be more than two thirds"), "{}", err);
+    let err = load_error("quorum-raft", "consensus = \"raft\"\n[limits]\nquorum = 2\n");
+    assert!(err.to_string().contains("quorum must be more than half"), "{}", err);
+    assert!(Config::load(&write_config("raft", "consensus = \"raft\"\n[limits]\nquorum = 3\n")).is_ok());
+  }
+}
@@ -0,0 +1,109 @@
+use super::codec;
+use super::vrf;
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair as _, UnparsedPublicKey};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::io;
+
+// Beginning of the PKCS#8 document of an Ed25519 key written by ring, which
+// is followed by the seed of the key.
+const PKCS8_PREFIX: [u8; 16] = [
+  0x30, 0x53, 0x02, 0x01, 0x01, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+  pub fn verify(&self, msg: &[u8], sig: &Signature) -> bool {
+    UnparsedPublicKey::new(&signature::ED25519, &self.0[..])
+      .verify(msg, &sig.0)
+      .is_ok()
+  }
+
+  // Returns the random output of the VRF proof when it has been made by this
+  // key for the message.
+  pub fn verify_proof(&self, msg: &[u8], proof: &vrf::Proof) -> Option<vrf::Output> {
+    vrf::verify(&self.0, msg, proof)
+  }
+}
+
+impl std::fmt::Display for PublicKey {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    for b in self.0.iter() {
+      write!(f, "{:02x?}", b)?;
+    }
+    Ok(())
+  }
+}
+
+// Parses the hexadecimal form of the key as it is displayed.
+impl std::str::FromStr for PublicKey {
+  type Err = io::Error;
+
+  fn from_str(s: &str) -> io::Result<Self> {
+    codec::from_hex(s)
+      .map(PublicKey)
+      .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("invalid public key {:?}", s)))
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Signature(Vec<u8>);
+
+impl AsRef<[u8]> for Signature {
+  fn as_ref(&self) -> &[u8] {
+    &self.0
+  }
+}
+
+pub struct KeyPair {
+  inner: Ed25519KeyPair,
+  // Seed of the key, from which the secret of the VRF is derived as ring
+  // doesn't expose it.
+  seed: [u8; 32],
+}
+
+impl KeyPair {
+  pub fn generate() -> io::Result<Self> {
+    KeyPair::from_pkcs8(&Self::generate_pkcs8()?)
+  }
+
+  // Returns a new key in the PKCS#8 format, which is how keys are stored.
+  pub fn generate_pkcs8() -> io::Result<Vec<u8>> {
+    let rng = SystemRandom::new();
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
+      .map_err(|_| io::Error::other("key generation failed"))?;
+
+    Ok(pkcs8.as_ref().to_vec())
+  }
+
+  pub fn from_pkcs8(bytes: &[u8]) -> io::Result<Self> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid PKCS#8 key");
+    let inner = Ed25519KeyPair::from_pkcs8(bytes).map_err(|_| invalid())?;
+
+    let seed: [u8; 32] = match bytes.get(..PKCS8_PREFIX.len()) {
+      Some(prefix) if prefix == PKCS8_PREFIX => bytes[prefix.len()..prefix.len() + 32].try_into().unwrap(),
+      _ => return Err(invalid()),
+    };
+
+    Ok(KeyPair { inner, seed })
+  }
+
+  pub fn public_key(&self) -> PublicKey {
+    let mut pk: [u8; 32] = Default::default();
+    pk.clone_from_slice(self.inner.public_key().as_ref());
+    PublicKey(pk)
+  }
+
+  pub fn sign(&self, msg: &[u8]) -> Signature {
+    Signature(self.inner.sign(msg).as_ref().to_vec())
+  }
+
+  // Returns the VRF proof of the message, which is the only one this key can
+  // make for it.
+  pub fn prove(&self, msg: &[u8]) -> vrf::Proof {
+    vrf::prove(&self.seed, msg)
+  }
+}
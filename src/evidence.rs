@@ -0,0 +1,200 @@
+use super::codec;
+use super::crypto::{KeyPair, Signature};
+use super::{Ack, BlockHeader, BlockID, Peer};
+use serde::{Deserialize, Serialize};
+
+// Evidence included in a single block, which keeps the blocks within a
+// datagram.
+pub const MAX_PER_BLOCK: usize = 4;
+
+// Two conflicting messages signed by the same validator for the same height
+// and round.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum Fault {
+  // The leader signed two different blocks, which are proven by their
+  // headers and signatures only so that the evidence stays small.
+  DoubleProposal(BlockHeader, Signature, BlockHeader, Signature),
+  // The validator acknowledged two different blocks.
+  DoubleAck(BlockID, Ack, BlockID, Ack),
+}
+
+impl Fault {
+  // Checks that the fault has been committed by the validator at the given
+  // index.
+  fn verify(&self, offender: usize, peers: &[Peer]) -> bool {
+    let peer = match peers.get(offender) {
+      Some(peer) => peer,
+      None => return false,
+    };
+
+    match self {
+      Fault::DoubleProposal(a, sig_a, b, sig_b) => {
+        a.has_leader(peer.get_addr())
+          && b.has_leader(peer.get_addr())
+          && a.get_height() == b.get_height()
+          && a.get_round() == b.get_round()
+          && a.hash() != b.hash()
+          && a.verify(peer.get_public_key(), sig_a)
+          && b.verify(peer.get_public_key(), sig_b)
+      }
+      Fault::DoubleAck(id_a, a, id_b, b) => {
+        a.get_index() == offender
+          && b.get_index() == offender
+          && a.get_height() == b.get_height()
+          && a.get_round() == b.get_round()
+          && id_a != id_b
+          && a.verify(id_a, peers)
+          && b.verify(id_b, peers)
+      }
+    }
+  }
+}
+
+// Fault of a validator signed by the validator reporting it. It is gossiped
+// to the other validators and included in a later block so that every node
+// agrees on the offenders.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Evidence {
+  fault: Fault,
+  offender: usize,
+  reporter: usize,
+  signature: Signature,
+}
+
+impl Evidence {
+  pub fn new(fault: Fault, offender: usize, reporter: usize, keypair: &KeyPair) -> Self {
+    let signature = keypair.sign(&Self::message(&fault, offender));
+
+    Evidence {
+      fault,
+      offender,
+      reporter,
+      signature,
+    }
+  }
+
+  pub fn get_fault(&self) -> &Fault {
+    &self.fault
+  }
+
+  pub fn get_offender(&self) -> usize {
+    self.offender
+  }
+
+  pub fn get_reporter(&self) -> usize {
+    self.reporter
+  }
+
+  pub fn verify(&self, peers: &[Peer]) -> bool {
+    let signed = match peers.get(self.reporter) {
+      Some(peer) => {
+        let msg = Self::message(&self.fault, self.offender);
+        peer.get_public_key().verify(&msg, &self.signature)
+      }
+      None => false,
+    };
+
+    signed && self.fault.verify(self.offender, peers)
+  }
+
+  fn message(fault: &Fault, offender: usize) -> Vec<u8> {
+    codec::encode_unbounded(&("evidence", fault, offender))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{Block, Genesis, Seed};
+  use std::net::SocketAddr;
+
+  fn validators(n: u16) -> (Vec<KeyPair>, Vec<Peer>) {
+    let keys: Vec<_> = (0..n).map(|_| KeyPair::generate().unwrap()).collect();
+    let peers = (0..n)
+      .map(|i| Peer::new(SocketAddr::from(([127, 0, 0, 1], 3000 + i)), keys[i as usize].public_key(), 1))
+      .collect();
+    (keys, peers)
+  }
+
+  // Signs a block of the validator at the given index for height 1 and round
+  // 0, which differs with the state root.
+  fn propose(keys: &[KeyPair], peers: &[Peer], index: usize, root: u8) -> (BlockHeader, Signature) {
+    let genesis = Genesis::new("test".to_string(), peers.to_vec(), Seed::default(), Vec::new());
+    let mut block = Block::genesis(&genesis).next(&keys[index], *peers[index].get_addr(), 0, Vec::new());
+    block.set_state_root([root; 32]);
+    block.sign(&keys[index]);
+    (block.get_header().clone(), block.get_signature().unwrap().clone())
+  }
+
+  fn double_proposal(keys: &[KeyPair], peers: &[Peer], index: usize) -> Fault {
+    let (a, sig_a) = propose(keys, peers, index, 1);
+    let (b, sig_b) = propose(keys, peers, index, 2);
+    Fault::DoubleProposal(a, sig_a, b, sig_b)
+  }
+
+  fn double_ack(keys: &[KeyPair], peers: &[Peer], index: usize) -> Fault {
+    let (a, b) = (propose(keys, peers, 0, 1).0.hash(), propose(keys, peers, 0, 2).0.hash());
+    Fault::DoubleAck(
+      a.clone(),
+      Ack::new(&a, 1, 0, index, &keys[index]),
+      b.clone(),
+      Ack::new(&b, 1, 0, index, &keys[index]),
+    )
+  }
+
+  #[test]
+  fn accepts_conflicting_messages() {
+    let (keys, peers) = validators(3);
+
+    let evidence = Evidence::new(double_proposal(&keys, &peers, 1), 1, 0, &keys[0]);
+    assert!(evidence.verify(&peers));
+
+    let evidence = Evidence::new(double_ack(&keys, &peers, 2), 2, 0, &keys[0]);
+    assert!(evidence.verify(&peers));
+  }
+
+  #[test]
+  fn rejects_forged_signatures() {
+    let (keys, peers) = validators(3);
+
+    // The reporter must sign the evidence with its own key.
+    let evidence = Evidence::new(double_ack(&keys, &peers, 2), 2, 0, &keys[1]);
+    assert!(!evidence.verify(&peers));
+
+    // The signature of the first header is reused for the second one.
+    let (a, sig_a) = propose(&keys, &peers, 1, 1);
+    let (b, _) = propose(&keys, &peers, 1, 2);
+    let fault = Fault::DoubleProposal(a, sig_a.clone(), b, sig_a);
+    assert!(!Evidence::new(fault, 1, 0, &keys[0]).verify(&peers));
+
+    // The second ack is signed by another validator.
+    let fault = match double_ack(&keys, &peers, 2) {
+      Fault::DoubleAck(id_a, a, id_b, _) => {
+        let b = Ack::new(&id_b, 1, 0, 2, &keys[1]);
+        Fault::DoubleAck(id_a, a, id_b, b)
+      }
+      _ => unreachable!(),
+    };
+    assert!(!Evidence::new(fault, 2, 0, &keys[0]).verify(&peers));
+  }
+
+  #[test]
+  fn rejects_wrong_indexes() {
+    let (keys, peers) = validators(3);
+
+    // The offender is not the leader of the blocks.
+    let evidence = Evidence::new(double_proposal(&keys, &peers, 1), 2, 0, &keys[0]);
+    assert!(!evidence.verify(&peers));
+
+    // The offender is not the validator of the acks.
+    let evidence = Evidence::new(double_ack(&keys, &peers, 2), 1, 0, &keys[0]);
+    assert!(!evidence.verify(&peers));
+
+    // The offender and the reporter are not validators.
+    let evidence = Evidence::new(double_ack(&keys, &peers, 2), 3, 0, &keys[0]);
+    assert!(!evidence.verify(&peers));
+    let evidence = Evidence::new(double_ack(&keys, &peers, 2), 2, 3, &keys[0]);
+    assert!(!evidence.verify(&peers));
+  }
+}
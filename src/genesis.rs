@@ -0,0 +1,207 @@
+use super::config::with_path;
+use super::crypto::PublicKey;
+use super::peer;
+use super::{Block, BlockID, KvStore, Peer, Seed};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+// Document every node of a network starts from. It defines the genesis block
+// so that networks with distinct documents have distinct chains, and their
+// nodes ignore each other.
+#[derive(Serialize, Clone, Debug)]
+pub struct Genesis {
+  chain_id: String,
+  validators: Vec<Peer>,
+  seed: Seed,
+  // Initial state of the application in the encoding it defines.
+  state: Vec<u8>,
+}
+
+// Genesis read from a TOML file such as:
+//
+//   chain_id = "testnet-1"
+//   seed = "3f1c...9e07"
+//
+//   [[validators]]
+//   addr = "127.0.0.1:3000"
+//   public_key = "9a0e...c2d1"
+//   weight = 2
+//
+//   [state]
+//   greeting = "hello"
+//
+// The weight of a validator is 1 when it is left out. The binary runs the
+// key/value store so the initial state is a table of its pairs.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct GenesisFile {
+  chain_id: String,
+  #[serde(deserialize_with = "parse")]
+  seed: Seed,
+  validators: Vec<Validator>,
+  #[serde(default)]
+  state: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Validator {
+  addr: SocketAddr,
+  #[serde(deserialize_with = "parse")]
+  public_key: PublicKey,
+  #[serde(default = "default_weight")]
+  weight: u64,
+}
+
+fn default_weight() -> u64 {
+  1
+}
+
+fn parse<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+  D: Deserializer<'de>,
+  T: FromStr,
+  T::Err: Display,
+{
+  let s = String::deserialize(deserializer)?;
+  s.parse().map_err(serde::de::Error::custom)
+}
+
+impl Genesis {
+  pub fn new(chain_id: String, validators: Vec<Peer>, seed: Seed, state: Vec<u8>) -> Self {
+    Genesis {
+      chain_id,
+      validators,
+      seed,
+      state,
+    }
+  }
+
+  // Reads the file and checks that a network can start with it.
+  pub fn load(path: &Path) -> io::Result<Self> {
+    let content = fs::read_to_string(path).map_err(|e| with_path(path, e))?;
+    let file: GenesisFile = toml::from_str(&content)
+      .map_err(|e| with_path(path, io::Error::new(io::ErrorKind::InvalidData, e)))?;
+
+    let validators = file
+      .validators
+      .into_iter()
+      .map(|v| Peer::new(v.addr, v.public_key, v.weight))
+      .collect();
+    let state = file
+      .state
+      .into_iter()
+      .map(|(k, v)| (k.into_bytes(), v.into_bytes()))
+      .collect();
+
+    let genesis = Genesis::new(file.chain_id, validators, file.seed, KvStore::encode_state(&state));
+    genesis.validate().map_err(|e| with_path(path, e))?;
+    Ok(genesis)
+  }
+
+  pub fn validate(&self) -> io::Result<()> {
+    let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+
+    if self.chain_id.is_empty() {
+      return invalid("chain ID must not be empty".to_string());
+    }
+    if self.validators.is_empty() {
+      return invalid("genesis must have at least one validator".to_string());
+    }
+
+    let mut addrs = HashSet::new();
+    let mut keys = HashSet::new();
+    let mut power: u64 = 0;
+    for v in &self.validators {
+      if !addrs.insert(v.get_addr()) {
+        return invalid(format!("validator {} is listed twice", v.get_addr()));
+      }
+      if !keys.insert(v.get_public_key()) {
+        return invalid(format!("validator {} has the public key of another one", v.get_addr()));
+      }
+      if v.get_weight() == 0 {
+        return invalid(format!("validator {} must have a positive weight", v.get_addr()));
+      }
+      // The quorums are computed on the total weight, which must fit in a u64.
+      power = match power.checked_add(v.get_weight()) {
+        Some(power) => power,
+        None => return invalid("total weight of the validators overflows".to_string()),
+      };
+    }
+
+    Ok(())
+  }
+
+  pub fn get_chain_id(&self) -> &str {
+    &self.chain_id
+  }
+
+  pub fn get_validators(&self) -> &[Peer] {
+    &self.validators
+  }
+
+  pub fn get_validator(&self, addr: &SocketAddr) -> Option<&Peer> {
+    self.validators.iter().find(|v| v.get_addr() == addr)
+  }
+
+  // Returns the voting power of all the validators.
+  pub fn get_power(&self) -> u64 {
+    peer::power(&self.validators, 0..self.validators.len())
+  }
+
+  pub fn get_seed(&self) -> &Seed {
+    &self.seed
+  }
+
+  pub fn get_state(&self) -> &[u8] {
+    &self.state
+  }
+
+  // Returns the ID of the genesis block, which identifies the network. The
+  // document is hashed with the unbounded encoding so that its state isn't
+  // limited to the size of a datagram.
+  pub fn hash(&self) -> BlockID {
+    Block::genesis(self).hash()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::crypto::KeyPair;
+
+  fn validator(port: u16, weight: u64) -> Peer {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    Peer::new(addr, KeyPair::generate().unwrap().public_key(), weight)
+  }
+
+  #[test]
+  fn accepts_weighted_validators() {
+    let validators = vec![validator(3000, 1), validator(3001, 3)];
+    let genesis = Genesis::new("test".to_string(), validators, Seed::default(), Vec::new());
+
+    assert!(genesis.validate().is_ok());
+    assert_eq!(genesis.get_power(), 4);
+  }
+
+  #[test]
+  fn rejects_overflowing_weights() {
+    let validators = vec![validator(3000, u64::MAX), validator(3001, 1)];
+    let genesis = Genesis::new("test".to_string(), validators, Seed::default(), Vec::new());
+
+    assert!(genesis.validate().is_err());
+  }
+
+  #[test]
+  fn hashes_a_state_larger_than_a_datagram() {
+    let genesis = |state| Genesis::new("test".to_string(), vec![validator(3000, 1)], Seed::default(), state);
+
+    assert_ne!(genesis(vec![0; 100_000]).hash(), genesis(vec![1; 100_000]).hash());
+  }
+}
@@ -0,0 +1,43 @@
+use super::codec;
+use super::crypto::{KeyPair, PublicKey, Signature};
+use super::BlockID;
+use serde::{Deserialize, Serialize};
+
+// Handshake between validators with the ID of their genesis block. The flag
+// is set when answering the hello of another validator, which isn't answered
+// again. It is signed so that nobody else can make a validator drop another
+// one with a hello of another genesis.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Hello {
+  genesis: BlockID,
+  answer: bool,
+  signature: Signature,
+}
+
+impl Hello {
+  pub fn new(genesis: BlockID, answer: bool, keypair: &KeyPair) -> Self {
+    let signature = keypair.sign(&Self::message(&genesis, answer));
+
+    Hello {
+      genesis,
+      answer,
+      signature,
+    }
+  }
+
+  pub fn get_genesis(&self) -> &BlockID {
+    &self.genesis
+  }
+
+  pub fn is_answer(&self) -> bool {
+    self.answer
+  }
+
+  pub fn verify(&self, public_key: &PublicKey) -> bool {
+    public_key.verify(&Self::message(&self.genesis, self.answer), &self.signature)
+  }
+
+  fn message(genesis: &BlockID, answer: bool) -> Vec<u8> {
+    codec::encode_unbounded(&("hello", genesis, answer))
+  }
+}
@@ -0,0 +1,233 @@
+use super::codec;
+use super::merkle::{self, MerkleProof, MerkleTree};
+use super::server::Application;
+use super::{Block, Transaction};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// Operation carried by the payload of a transaction.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum KvOp {
+  Set(Vec<u8>, Vec<u8>),
+  Delete(Vec<u8>),
+}
+
+// Pair of the state with its inclusion proof.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KvEntry {
+  key: Vec<u8>,
+  value: Vec<u8>,
+  proof: MerkleProof,
+}
+
+impl KvEntry {
+  fn verify(&self, root: &merkle::Hash) -> bool {
+    self.proof.verify(&leaf(&self.key, &self.value), root)
+  }
+}
+
+// Answer to the query of a key, which proves its value or its absence
+// against the state root.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum KvProof {
+  Present(KvEntry),
+  // The pairs surrounding the key in the state, which are missing at the
+  // ends of the state.
+  Absent(Option<KvEntry>, Option<KvEntry>),
+}
+
+impl KvProof {
+  pub fn get_value(&self) -> Option<&[u8]> {
+    match self {
+      KvProof::Present(entry) => Some(&entry.value),
+      KvProof::Absent(..) => None,
+    }
+  }
+
+  // Checks the proof for the key against the state root. The leaves are
+  // ordered by key so two adjacent leaves around the key prove its absence.
+  pub fn verify(&self, key: &[u8], root: &merkle::Hash) -> bool {
+    match self {
+      KvProof::Present(entry) => entry.key == key && entry.verify(root),
+      KvProof::Absent(left, right) => {
+        let valid = left.iter().chain(right).all(|e| e.verify(root))
+          && left.as_ref().is_none_or(|e| e.key.as_slice() < key)
+          && right.as_ref().is_none_or(|e| key < e.key.as_slice());
+
+        valid
+          && match (left, right) {
+            (Some(l), Some(r)) => r.proof.get_index() == l.proof.get_index() + 1,
+            (Some(l), None) => l.proof.get_index() + 1 == l.proof.get_size(),
+            (None, Some(r)) => r.proof.get_index() == 0,
+            (None, None) => *root == merkle::root(&[]),
+          }
+      }
+    }
+  }
+}
+
+fn leaf(key: &[u8], value: &[u8]) -> Vec<u8> {
+  codec::encode_unbounded(&(key, value))
+}
+
+// Replicated key/value store. The state root is the root of the Merkle tree
+// over the pairs ordered by key, which follows the changes of each block.
+#[derive(Clone, Default)]
+pub struct KvStore {
+  state: BTreeMap<Vec<u8>, Vec<u8>>,
+  tree: MerkleTree,
+}
+
+impl KvStore {
+  pub fn new() -> Self {
+    Default::default()
+  }
+
+  // Encodes the pairs of the initial state of the store for the genesis.
+  pub fn encode_state(state: &BTreeMap<Vec<u8>, Vec<u8>>) -> Vec<u8> {
+    codec::encode_unbounded(state)
+  }
+
+  // Returns the position of the key among the ones of the state, which is
+  // the index of its leaf.
+  fn index(&self, key: &[u8]) -> usize {
+    self.state.keys().take_while(|k| k.as_slice() < key).count()
+  }
+
+  fn entry(&self, index: usize) -> Option<KvEntry> {
+    let (key, value) = self.state.iter().nth(index)?;
+    Some(KvEntry {
+      key: key.clone(),
+      value: value.clone(),
+      proof: self.tree.prove(index)?,
+    })
+  }
+
+  // Returns the value of the key with the proof against the state root.
+  pub fn prove(&self, key: &[u8]) -> KvProof {
+    let index = self.index(key);
+
+    if self.state.contains_key(key) {
+      KvProof::Present(self.entry(index).unwrap())
+    } else {
+      let left = index.checked_sub(1).and_then(|i| self.entry(i));
+      KvProof::Absent(left, self.entry(index))
+    }
+  }
+
+  fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+    let index = self.index(&key);
+    if self.state.contains_key(&key) {
+      self.tree.set(index, &leaf(&key, &value));
+    } else {
+      self.tree.insert(index, &leaf(&key, &value));
+    }
+    self.state.insert(key, value);
+  }
+
+  fn delete(&mut self, key: &[u8]) {
+    if self.state.remove(key).is_some() {
+      self.tree.remove(self.index(key));
+    }
+  }
+}
+
+impl Application for KvStore {
+  fn check_tx(&self, tx: &Transaction) -> bool {
+    codec::decode::<KvOp>(tx.get_payload()).is_ok()
+  }
+
+  // Payloads that are not operations are ignored.
+  fn deliver_block(&mut self, block: &Block) {
+    for tx in block.get_txs() {
+      match codec::decode(tx.get_payload()) {
+        Ok(KvOp::Set(key, value)) => self.set(key, value),
+        Ok(KvOp::Delete(key)) => self.delete(&key),
+        Err(_) => (),
+      };
+    }
+  }
+
+  fn commit(&mut self) -> merkle::Hash {
+    self.tree.update();
+    self.tree.root()
+  }
+
+  // A genesis state that can't be decoded, like an empty one, starts the
+  // store empty.
+  fn reset(&mut self, state: &[u8]) {
+    self.state = codec::decode_unbounded(state).unwrap_or_default();
+    let leaves: Vec<_> = self.state.iter().map(|(k, v)| leaf(k, v)).collect();
+    self.tree = MerkleTree::new(&leaves);
+  }
+
+  fn snapshot(&self) -> Box<dyn Application> {
+    Box::new(self.clone())
+  }
+
+  // The query is the key and the answer is the encoded proof.
+  fn query(&self, data: &[u8]) -> Vec<u8> {
+    codec::encode_unbounded(&self.prove(data))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::crypto::KeyPair;
+  use crate::{Genesis, Seed};
+  use std::net::SocketAddr;
+
+  fn block(ops: Vec<KvOp>) -> Block {
+    let keypair = KeyPair::generate().unwrap();
+    let leader = SocketAddr::from(([127, 0, 0, 1], 3000));
+    let txs = ops
+      .iter()
+      .enumerate()
+      .map(|(i, op)| Transaction::new(i as u64 + 1, codec::encode_unbounded(op), &keypair))
+      .collect();
+
+    let genesis = Block::genesis(&Genesis::new("test".to_string(), Vec::new(), Seed::default(), Vec::new()));
+    genesis.next(&keypair, leader, 0, txs)
+  }
+
+  fn set(key: u8, value: u8) -> KvOp {
+    KvOp::Set(vec![key], vec![value])
+  }
+
+  #[test]
+  fn follows_the_changes_of_the_blocks() {
+    let initial = vec![(vec![10], vec![0]), (vec![20], vec![0])].into_iter().collect();
+    let mut store = KvStore::new();
+    store.reset(&KvStore::encode_state(&initial));
+
+    let blocks = vec![
+      vec![set(15, 1), set(5, 1), set(25, 1)],
+      vec![set(10, 2), KvOp::Delete(vec![20]), KvOp::Delete(vec![30])],
+      vec![set(1, 3), KvOp::Delete(vec![1]), set(15, 3)],
+      vec![KvOp::Delete(vec![5]), KvOp::Delete(vec![10]), KvOp::Delete(vec![15]), KvOp::Delete(vec![25])],
+      vec![set(7, 4)],
+    ];
+    for ops in blocks {
+      store.deliver_block(&block(ops));
+      let root = store.commit();
+
+      let leaves: Vec<_> = store.state.iter().map(|(k, v)| leaf(k, v)).collect();
+      assert_eq!(root, merkle::root(&leaves));
+      for key in 0..30 {
+        let proof = store.prove(&[key]);
+        assert!(proof.verify(&[key], &root), "key {}", key);
+        assert_eq!(proof.get_value(), store.state.get(&vec![key]).map(Vec::as_slice));
+      }
+    }
+  }
+  #[test]
+  fn commits_an_empty_store() {
+    let mut store = KvStore::new();
+    assert_eq!(store.commit(), merkle::root(&[]));
+
+    store.deliver_block(&block(vec![set(1, 1), KvOp::Delete(vec![1])]));
+    assert_eq!(store.commit(), merkle::root(&[]));
+    assert!(store.prove(&[1]).verify(&[1], &merkle::root(&[])));
+  }
+}
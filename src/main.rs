@@ -1,61 +1,207 @@
 mod server;
+mod cli;
 mod client;
+mod config;
+mod bft;
 mod block;
+mod codec;
+mod crypto;
+mod evidence;
+mod genesis;
+mod hello;
+mod kv;
+mod merkle;
+mod peer;
+mod query;
+mod raft;
+mod sync;
+mod transaction;
+mod view_change;
+mod vrf;
 
+pub use bft::{Prepare, PrepareCertificate, Proposal};
 pub use block::*;
+pub use evidence::{Evidence, Fault};
+pub use genesis::Genesis;
+pub use hello::Hello;
+pub use kv::{KvOp, KvProof, KvStore};
+pub use merkle::MerkleProof;
+pub use peer::Peer;
+pub use query::ChainQuery;
+pub use raft::{AppendEntries, AppendResponse, RequestVote, Vote};
+pub use sync::BlockRequest;
+pub use transaction::{Receipt, Transaction, TxID};
+pub use view_change::ViewChange;
 
 use serde::{Serialize, Deserialize};
 use std::net::SocketAddr;
-use server::Server;
+use std::path::Path;
+use std::time::Duration;
+use server::{Consensus, Notification, Options, Server};
 use client::Client;
+use crypto::KeyPair;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum Event {
     ProposeBlock(Block),
+    AckBlock(BlockID, Ack),
     ValidateBlock(Block),
+    ViewChange(ViewChange),
+    Propose(Proposal),
+    Prepare(Prepare),
+    Commit(BlockID, Ack),
+    RequestVote(RequestVote),
+    Vote(Vote),
+    AppendEntries(AppendEntries),
+    AppendResponse(AppendResponse),
+    Evidence(Evidence),
+    GetBlocks(BlockRequest),
+    Blocks(Vec<Block>),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum Message {
     Event(Event),
-    Request(Vec<u8>),
+    Request(Transaction),
+    // Query of a client for the application, and the answer about the state
+    // reached after the given block.
+    Query(Vec<u8>),
+    QueryResult(BlockID, Vec<u8>),
+    // Sent to the client that submitted a transaction once it is committed.
+    Receipt(Receipt),
+    GetChain(ChainQuery),
+    ChainResult(Vec<Block>),
+    // Handshake between validators.
+    Hello(Hello),
 }
 
-fn main() {
-    simple_logger::init().unwrap();
+// Waits for the best branch of the server to reach the height and returns
+// the block that reached it.
+fn wait_height(srv: &Server, height: u64) -> Block {
+    let mut tip = None;
+    srv.wait(|n| {
+        let block = match n {
+            Notification::Block(block) => block,
+            Notification::Reorg { removed, mut added } => {
+                log::info!("Chain reorganized: {} blocks replaced by {}", removed.len(), added.len());
+                added.pop().unwrap()
+            }
+        };
+        let reached = block.get_height() >= height;
+        tip = Some(block);
+        reached
+    });
+    tip.unwrap()
+}
 
+// Runs a cluster of servers on the local host, and sends them transactions
+// and queries.
+fn demo(consensus: Consensus) -> std::io::Result<()> {
     let n: usize = 5;
     let mut peers = Vec::new();
+    let mut keypairs = Vec::new();
     let mut srvs = Vec::new();
 
-    // Create the addresses.
+    // Create the addresses and the identity of each server.
     for i in 0..n {
         let addr: SocketAddr = ([127, 0, 0, 1], 3000+(i as u16)).into();
-        peers.push(addr.clone());
+        let keypair = KeyPair::generate().unwrap();
+        peers.push(Peer::new(addr, keypair.public_key(), 1));
+        keypairs.push(keypair);
     }
 
+    // The keys of the validators are new so each run starts another network,
+    // in which the key 0 is already set.
+    let state = vec![(vec![0], vec![0])].into_iter().collect();
+    let genesis = Genesis::new("demo".to_string(), peers.clone(), Seed::default(), KvStore::encode_state(&state));
+
     // Create and start the servers.
-    for i in 0..n {
-        let mut srv = Server::new(&peers[i], peers.clone()).unwrap();
+    for (peer, keypair) in peers.iter().zip(keypairs) {
+        let opts = Options { consensus, ..Default::default() };
+        let mut srv = Server::new(peer.get_addr(), keypair, &genesis, KvStore::new(), opts).unwrap();
         srv.start();
         srvs.push(srv);
     }
 
-    // Send a request to each server. They will all try to create a block.
+    // Send a transaction setting a key to a server which forwards it to the
+    // leader, and wait for its receipt and for the block creation on each
+    // server before the next one. The nonce grows with each transaction of the
+    // client.
     let cl = Client::new().unwrap();
-    for to in peers {
-        cl.send_to(&to, Message::Request(vec![1, 2, 3]));
+    let sender = KeyPair::generate().unwrap();
+    for i in 0..3 {
+        let to = peers[i % n].get_addr();
+        let op = KvOp::Set(vec![i as u8], vec![1, 2, 3]);
+        let tx = Transaction::new(i as u64 + 1, codec::encode_unbounded(&op), &sender);
+        let id = cl.submit(to, tx.clone()).unwrap();
+        let receipt = cl.wait_receipt(&id, Duration::from_secs(5)).unwrap();
+
+        for srv in &srvs {
+            wait_height(srv, i as u64 + 1);
+        }
+        let block = cl.get_block_by_id(to, receipt.get_block()).unwrap().unwrap();
+
+        let included = block.hash() == *receipt.get_block() && block.get_header().has_tx(&tx, receipt.get_proof());
+        log::info!("Transaction {} committed in block {} at height {}: {}", id, receipt.get_block(), receipt.get_height(), included);
     }
 
-    // Wait for a block creation on each server to continue.
-    for srv in &srvs {
-        srv.wait(|_| true);
-        srv.wait(|_| true);
-        srv.wait(|_| true);
+    // Query a key with the proof of its value in the state whose root is in
+    // the header of the tip.
+    let (id, res) = cl.query(peers[0].get_addr(), vec![1]).unwrap();
+    let proof: KvProof = codec::decode(&res).unwrap();
+    let block = cl.get_block_by_id(peers[0].get_addr(), &id).unwrap().unwrap();
+    let valid = proof.verify(&[1], block.get_state_root());
+    log::info!("Key 1 has value {:?} in block {} with a valid proof: {}", proof.get_value(), id, valid);
+
+    // Read the chain of another server page by page from the start.
+    let addr = peers[1].get_addr();
+    let tip = cl.get_tip(addr).unwrap();
+    let mut blocks = Vec::new();
+    loop {
+        let page = cl.get_blocks(addr, blocks.len() as u64, 2).unwrap();
+        if page.is_empty() {
+            break;
+        }
+        blocks.extend(page);
     }
+    let last = cl.get_block(addr, tip.get_height()).unwrap();
+    let valid = blocks.windows(2).all(|w| w[1].get_parent() == &w[0].hash())
+        && last.is_some_and(|b| b.hash() == tip.hash());
+    log::info!("Read {} blocks from {} up to block {} with valid links: {}", blocks.len(), addr, tip.hash(), valid);
 
     // Stop and clean each server. It waits for thread to close.
     for srv in srvs {
         srv.stop();
     }
+
+    Ok(())
+}
+
+fn main() {
+    simple_logger::init().unwrap();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let res = match args.as_slice() {
+        ["keygen", path] => cli::keygen(Path::new(path)),
+        ["node", path] => cli::node(Path::new(path)),
+        ["submit", node, key, nonce, op @ ..] => cli::submit(node, key, nonce, op),
+        ["tip", node] => cli::tip(node),
+        ["block", node, height] => cli::block(node, height),
+        ["inspect", path] => cli::inspect(Path::new(path)),
+        ["demo"] | ["demo", "ack"] => demo(Consensus::Ack),
+        ["demo", "bft"] => demo(Consensus::Bft),
+        ["demo", "raft"] => demo(Consensus::Raft),
+        _ => {
+            eprintln!("{}", cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(e) = res {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,461 @@
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+pub type Hash = [u8; digest::SHA256_OUTPUT_LEN];
+
+// Prefixes of the hashes of the leaves and of the inner nodes, so that a leaf
+// can't be passed off as a node.
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+fn sha256(parts: &[&[u8]]) -> Hash {
+  let mut ctx = digest::Context::new(&digest::SHA256);
+  for part in parts {
+    ctx.update(part);
+  }
+
+  let mut h: Hash = Default::default();
+  h.clone_from_slice(ctx.finish().as_ref());
+  h
+}
+
+fn hash_leaf(leaf: &[u8]) -> Hash {
+  sha256(&[&[LEAF_PREFIX], leaf])
+}
+
+fn hash_node(left: &Hash, right: &Hash) -> Hash {
+  sha256(&[&[NODE_PREFIX], left, right])
+}
+
+// Largest power of two smaller than n, which is at least 2.
+fn split(n: usize) -> usize {
+  let mut k = 1;
+  while k * 2 < n {
+    k *= 2;
+  }
+  k
+}
+
+// Returns the root of the tree built over the leaves as in RFC 6962: the
+// left subtree holds the largest power of two of leaves and the rest goes to
+// the right subtree. The root of an empty tree is the hash of nothing.
+pub fn root(leaves: &[Vec<u8>]) -> Hash {
+  match leaves.len() {
+    0 => sha256(&[]),
+    1 => hash_leaf(&leaves[0]),
+    n => {
+      let k = split(n);
+      hash_node(&root(&leaves[..k]), &root(&leaves[k..]))
+    }
+  }
+}
+
+// Returns the proof that the leaf at the given index belongs to the tree.
+pub fn prove(leaves: &[Vec<u8>], index: usize) -> Option<MerkleProof> {
+  if index >= leaves.len() {
+    return None;
+  }
+
+  Some(MerkleProof {
+    index: index as u64,
+    size: leaves.len() as u64,
+    path: path(leaves, index),
+  })
+}
+
+// Returns the proofs of all the leaves, which is faster than proving them one
+// by one.
+pub fn prove_all(leaves: &[Vec<u8>]) -> Vec<MerkleProof> {
+  let size = leaves.len() as u64;
+  let (_, paths) = paths(leaves);
+
+  paths
+    .into_iter()
+    .enumerate()
+    .map(|(index, path)| MerkleProof {
+      index: index as u64,
+      size,
+      path,
+    })
+    .collect()
+}
+
+// Returns the root of the tree and the path of every leaf.
+fn paths(leaves: &[Vec<u8>]) -> (Hash, Vec<Vec<Hash>>) {
+  if leaves.len() <= 1 {
+    return (root(leaves), leaves.iter().map(|_| Vec::new()).collect());
+  }
+
+  let k = split(leaves.len());
+  let (left, mut left_paths) = paths(&leaves[..k]);
+  let (right, mut right_paths) = paths(&leaves[k..]);
+  left_paths.iter_mut().for_each(|path| path.push(right));
+  right_paths.iter_mut().for_each(|path| path.push(left));
+
+  left_paths.extend(right_paths);
+  (hash_node(&left, &right), left_paths)
+}
+
+// Hashes of the siblings from the leaf up to the root.
+fn path(leaves: &[Vec<u8>], index: usize) -> Vec<Hash> {
+  let n = leaves.len();
+  if n <= 1 {
+    return Vec::new();
+  }
+
+  let k = split(n);
+  let (mut path, sibling) = if index < k {
+    (path(&leaves[..k], index), root(&leaves[k..]))
+  } else {
+    (path(&leaves[k..], index - k), root(&leaves[..k]))
+  };
+  path.push(sibling);
+  path
+}
+
+// Tree kept in memory with the hashes of its complete subtrees, which are
+// the subtrees of 2^k leaves starting at a multiple of 2^k. The tree of RFC
+// 6962 is made of the complete subtrees given by the bits of its size, so
+// that changing a leaf only hashes its ancestors again. Inserting or removing
+// a leaf shifts the ones after it, and the subtrees covering them are hashed
+// again. The changes are applied to the hashes by update.
+#[derive(Clone)]
+pub struct MerkleTree {
+  // Hashes of the leaves, then of the complete subtrees of 2, 4... leaves.
+  levels: Vec<Vec<Hash>>,
+  // Leaves changed since the last update.
+  changed: BTreeSet<usize>,
+  // First leaf shifted since the last update.
+  shifted: Option<usize>,
+}
+
+impl Default for MerkleTree {
+  // The tree without leaves still has the level of the leaves.
+  fn default() -> Self {
+    MerkleTree::new(&[])
+  }
+}
+
+impl MerkleTree {
+  pub fn new(leaves: &[Vec<u8>]) -> Self {
+    let mut tree = MerkleTree {
+      levels: vec![leaves.iter().map(|l| hash_leaf(l)).collect()],
+      changed: BTreeSet::new(),
+      shifted: Some(0),
+    };
+    tree.update();
+    tree
+  }
+
+  pub fn len(&self) -> usize {
+    self.levels.first().map_or(0, Vec::len)
+  }
+
+  pub fn set(&mut self, index: usize, leaf: &[u8]) {
+    self.levels[0][index] = hash_leaf(leaf);
+    self.changed.insert(index);
+  }
+
+  pub fn insert(&mut self, index: usize, leaf: &[u8]) {
+    if self.levels.is_empty() {
+      self.levels.push(Vec::new());
+    }
+    self.levels[0].insert(index, hash_leaf(leaf));
+    self.shift(index);
+  }
+
+  pub fn remove(&mut self, index: usize) {
+    self.levels[0].remove(index);
+    self.shift(index);
+  }
+
+  fn shift(&mut self, index: usize) {
+    self.shifted = Some(self.shifted.map_or(index, |i| i.min(index)));
+  }
+
+  // Hashes again the complete subtrees covering the changed and the shifted
+  // leaves.
+  pub fn update(&mut self) {
+    let mut changed = std::mem::take(&mut self.changed);
+    let mut from = self.shifted.take().unwrap_or(usize::MAX);
+
+    for k in 1.. {
+      let n = self.levels[k - 1].len() / 2;
+      if n == 0 {
+        self.levels.truncate(k);
+        break;
+      }
+      if self.levels.len() == k {
+        self.levels.push(Vec::new());
+      }
+
+      from = (from / 2).min(self.levels[k].len()).min(n);
+      changed = changed.into_iter().map(|i| i / 2).filter(|&i| i < from).collect();
+
+      let (below, level) = self.levels.split_at_mut(k);
+      let below = &below[k - 1];
+      level[0].truncate(from);
+      level[0].extend((from..n).map(|i| hash_node(&below[2 * i], &below[2 * i + 1])));
+      for &i in &changed {
+        level[0][i] = hash_node(&below[2 * i], &below[2 * i + 1]);
+      }
+    }
+  }
+
+  // Returns the complete subtrees of the tree from left to right, as their
+  // level and index.
+  fn subtrees(&self) -> Vec<(usize, usize)> {
+    let n = self.len();
+    let mut start = 0;
+    (0..self.levels.len())
+      .rev()
+      .filter(|k| n & (1 << k) != 0)
+      .map(|k| {
+        let subtree = (k, start >> k);
+        start += 1 << k;
+        subtree
+      })
+      .collect()
+  }
+
+  fn fold(&self, subtrees: &[(usize, usize)]) -> Option<Hash> {
+    let mut hashes = subtrees.iter().rev().map(|&(k, i)| self.levels[k][i]);
+    let last = hashes.next()?;
+    Some(hashes.fold(last, |h, left| hash_node(&left, &h)))
+  }
+
+  // Returns the root as of the last update, which is the same as the one of
+  // the leaves given to root.
+  pub fn root(&self) -> Hash {
+    self.fold(&self.subtrees()).unwrap_or_else(|| sha256(&[]))
+  }
+
+  // Returns the proof of the leaf at the given index as of the last update.
+  pub fn prove(&self, index: usize) -> Option<MerkleProof> {
+    if index >= self.len() {
+      return None;
+    }
+
+    let subtrees = self.subtrees();
+    let j = subtrees.iter().position(|&(k, i)| index >> k == i).unwrap();
+
+    // Siblings inside the complete subtree of the leaf, then the root of the
+    // subtrees on its right and the subtrees on its left.
+    let mut path: Vec<Hash> = (0..subtrees[j].0).map(|k| self.levels[k][(index >> k) ^ 1]).collect();
+    path.extend(self.fold(&subtrees[j + 1..]));
+    path.extend(subtrees[..j].iter().rev().map(|&(k, i)| self.levels[k][i]));
+
+    Some(MerkleProof {
+      index: index as u64,
+      size: self.len() as u64,
+      path,
+    })
+  }
+}
+
+// Inclusion proof of a leaf in a tree of the given size, made of the hashes
+// of the siblings of the path from the leaf to the root.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MerkleProof {
+  index: u64,
+  size: u64,
+  path: Vec<Hash>,
+}
+
+impl MerkleProof {
+  pub fn get_index(&self) -> u64 {
+    self.index
+  }
+
+  pub fn get_size(&self) -> u64 {
+    self.size
+  }
+
+  // Checks that the leaf is in the tree with the given root, following the
+  // verification of RFC 9162.
+  pub fn verify(&self, leaf: &[u8], root: &Hash) -> bool {
+    if self.index >= self.size {
+      return false;
+    }
+
+    let (mut fi, mut si) = (self.index, self.size - 1);
+    let mut h = hash_leaf(leaf);
+    for sibling in &self.path {
+      if si == 0 {
+        return false;
+      }
+
+      if fi & 1 == 1 || fi == si {
+        h = hash_node(sibling, &h);
+        while fi & 1 == 0 && fi != 0 {
+          fi >>= 1;
+          si >>= 1;
+        }
+      } else {
+        h = hash_node(&h, sibling);
+      }
+      fi >>= 1;
+      si >>= 1;
+    }
+
+    si == 0 && h == *root
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn leaves(n: usize) -> Vec<Vec<u8>> {
+    (0..n).map(|i| vec![i as u8; i % 5 + 1]).collect()
+  }
+
+  #[test]
+  fn proves_every_leaf() {
+    for n in 1..40 {
+      let leaves = leaves(n);
+      let root = root(&leaves);
+      let all = prove_all(&leaves);
+      assert_eq!(all.len(), n);
+
+      for (i, leaf) in leaves.iter().enumerate() {
+        let proof = prove(&leaves, i).unwrap();
+        assert!(proof.verify(leaf, &root), "leaf {} of {}", i, n);
+        assert_eq!(all[i].path, proof.path, "leaf {} of {}", i, n);
+        assert!(all[i].verify(leaf, &root), "leaf {} of {}", i, n);
+      }
+    }
+  }
+
+  #[test]
+  fn rejects_wrong_leaf() {
+    for n in 1..40 {
+      let leaves = leaves(n);
+      let root = root(&leaves);
+
+      for i in 0..n {
+        let proof = prove(&leaves, i).unwrap();
+        assert!(!proof.verify(b"other", &root));
+        if n > 1 {
+          assert!(!proof.verify(&leaves[(i + 1) % n], &root));
+        }
+      }
+    }
+  }
+
+  // The size is only bound to the root through the shape of the path, so a
+  // wrong size is caught when the path of the index has another length in a
+  // tree of that size.
+  #[test]
+  fn rejects_wrong_size_and_index() {
+    for n in 2..40 {
+      let leaves = leaves(n);
+      let root = root(&leaves);
+
+      for i in 0..n {
+        let proof = prove(&leaves, i).unwrap();
+        let mut sizes = vec![i, 1];
+        if n.is_power_of_two() {
+          sizes.extend([n / 2, 2 * n]);
+        }
+
+        for size in sizes {
+          let wrong = MerkleProof {
+            size: size as u64,
+            ..proof.clone()
+          };
+          assert!(!wrong.verify(&leaves[i], &root), "leaf {} of {} as {}", i, n, size);
+        }
+
+        let wrong = MerkleProof {
+          index: proof.index ^ 1,
+          ..proof.clone()
+        };
+        assert!(!wrong.verify(&leaves[i], &root));
+      }
+    }
+  }
+
+  #[test]
+  fn rejects_proof_of_another_tree() {
+    let (a, b) = (leaves(7), leaves(8));
+    let proof = prove(&a, 3).unwrap();
+
+    assert!(!proof.verify(&a[3], &root(&b)));
+    assert!(prove(&a, 7).is_none());
+    assert_eq!(root(&[]), sha256(&[]));
+  }
+
+  // Checks the root and the proofs of the tree against the ones computed
+  // from the leaves.
+  fn assert_same(tree: &MerkleTree, leaves: &[Vec<u8>]) {
+    assert_eq!(tree.len(), leaves.len());
+    assert_eq!(tree.root(), root(leaves), "root of {}", leaves.len());
+    for i in 0..leaves.len() {
+      assert_eq!(tree.prove(i).unwrap().path, prove(leaves, i).unwrap().path, "leaf {} of {}", i, leaves.len());
+    }
+    assert!(tree.prove(leaves.len()).is_none());
+  }
+
+  #[test]
+  fn builds_the_same_tree() {
+    for n in 0..40 {
+      assert_same(&MerkleTree::new(&leaves(n)), &leaves(n));
+    }
+  }
+
+  #[test]
+  fn updates_the_tree() {
+    let mut leaves = leaves(20);
+    let mut tree = MerkleTree::new(&leaves);
+
+    // Changes of a single leaf, then of several ones before each update.
+    let changes: &[&[(char, usize)]] = &[
+      &[('s', 7)],
+      &[('i', 0)],
+      &[('i', 21)],
+      &[('r', 3)],
+      &[('r', 20)],
+      &[('s', 2), ('s', 19), ('s', 8)],
+      &[('i', 5), ('s', 15), ('r', 11)],
+      &[('s', 9), ('i', 9), ('r', 1), ('s', 0)],
+      &[('r', 0), ('r', 0), ('r', 0), ('r', 0), ('r', 0), ('r', 0), ('r', 0), ('r', 0)],
+    ];
+    for (n, ops) in changes.iter().enumerate() {
+      for &(op, i) in ops.iter() {
+        let leaf = vec![0xff, n as u8, i as u8];
+        match op {
+          's' => {
+            tree.set(i, &leaf);
+            leaves[i] = leaf;
+          }
+          'i' => {
+            tree.insert(i, &leaf);
+            leaves.insert(i, leaf);
+          }
+          _ => {
+            tree.remove(i);
+            leaves.remove(i);
+          }
+        }
+      }
+      tree.update();
+      assert_same(&tree, &leaves);
+    }
+
+    // Down to an empty tree and up again.
+    while !leaves.is_empty() {
+      tree.remove(leaves.len() - 1);
+      leaves.pop();
+      tree.update();
+      assert_same(&tree, &leaves);
+    }
+    for i in 0..9 {
+      tree.insert(0, &[i]);
+      leaves.insert(0, vec![i]);
+      tree.update();
+      assert_same(&tree, &leaves);
+    }
+  }
+}
@@ -0,0 +1,38 @@
+use super::crypto::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Peer {
+  addr: SocketAddr,
+  public_key: PublicKey,
+  // Voting power of the validator in the quorums.
+  weight: u64,
+}
+
+impl Peer {
+  pub fn new(addr: SocketAddr, public_key: PublicKey, weight: u64) -> Self {
+    Peer { addr, public_key, weight }
+  }
+
+  pub fn get_addr(&self) -> &SocketAddr {
+    &self.addr
+  }
+
+  pub fn get_public_key(&self) -> &PublicKey {
+    &self.public_key
+  }
+
+  pub fn get_weight(&self) -> u64 {
+    self.weight
+  }
+}
+
+// Returns the voting power of the validators at the given distinct indexes.
+pub fn power(peers: &[Peer], indexes: impl IntoIterator<Item = usize>) -> u64 {
+  indexes
+    .into_iter()
+    .filter_map(|i| peers.get(i))
+    .map(Peer::get_weight)
+    .sum()
+}
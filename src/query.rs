@@ -0,0 +1,18 @@
+use super::BlockID;
+use serde::{Deserialize, Serialize};
+
+// Query of a client about the chain of a node. The answer is the blocks
+// found ordered by height, which is empty when there is none.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ChainQuery {
+  // Last block of the best branch.
+  Tip,
+  // Block of the best branch at the given height.
+  Height(u64),
+  // Block with the given ID, even outside of the best branch.
+  Block(BlockID),
+  // At most the given number of blocks of the best branch starting at the
+  // given height. The answer can be shorter to fit in a datagram, and the
+  // next page starts after its last block.
+  Range(u64, u64),
+}
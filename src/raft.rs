@@ -0,0 +1,132 @@
+use super::Block;
+use serde::{Deserialize, Serialize};
+
+// Messages of the Raft engine. The nodes trust each other so the messages are
+// not signed and the sender is identified by the address of the datagram. The
+// log entries are the blocks themselves and the term of an entry is the round
+// of its block.
+
+// Sent by a candidate to ask for the vote of the other nodes in a term.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RequestVote {
+  term: u64,
+  last_height: u64,
+  last_term: u64,
+}
+
+impl RequestVote {
+  pub fn new(term: u64, last_height: u64, last_term: u64) -> Self {
+    RequestVote {
+      term,
+      last_height,
+      last_term,
+    }
+  }
+
+  pub fn get_term(&self) -> u64 {
+    self.term
+  }
+
+  pub fn get_last_height(&self) -> u64 {
+    self.last_height
+  }
+
+  pub fn get_last_term(&self) -> u64 {
+    self.last_term
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Vote {
+  term: u64,
+  granted: bool,
+}
+
+impl Vote {
+  pub fn new(term: u64, granted: bool) -> Self {
+    Vote { term, granted }
+  }
+
+  pub fn get_term(&self) -> u64 {
+    self.term
+  }
+
+  pub fn is_granted(&self) -> bool {
+    self.granted
+  }
+}
+
+// Sent by the leader to replicate the blocks following the given previous
+// one. It is also sent without any block as a heartbeat.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AppendEntries {
+  term: u64,
+  prev_height: u64,
+  prev_term: u64,
+  blocks: Vec<Block>,
+  commit: u64,
+}
+
+impl AppendEntries {
+  pub fn new(term: u64, prev_height: u64, prev_term: u64, blocks: Vec<Block>, commit: u64) -> Self {
+    AppendEntries {
+      term,
+      prev_height,
+      prev_term,
+      blocks,
+      commit,
+    }
+  }
+
+  pub fn get_term(&self) -> u64 {
+    self.term
+  }
+
+  pub fn get_prev_height(&self) -> u64 {
+    self.prev_height
+  }
+
+  pub fn get_prev_term(&self) -> u64 {
+    self.prev_term
+  }
+
+  pub fn get_blocks(&self) -> &[Block] {
+    &self.blocks
+  }
+
+  pub fn get_commit(&self) -> u64 {
+    self.commit
+  }
+}
+
+// Answer of a follower to the leader. The height is the last block matching
+// the log of the leader on success, or the last block of the follower
+// otherwise so that the leader knows where to resume.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AppendResponse {
+  term: u64,
+  success: bool,
+  height: u64,
+}
+
+impl AppendResponse {
+  pub fn new(term: u64, success: bool, height: u64) -> Self {
+    AppendResponse {
+      term,
+      success,
+      height,
+    }
+  }
+
+  pub fn get_term(&self) -> u64 {
+    self.term
+  }
+
+  pub fn is_success(&self) -> bool {
+    self.success
+  }
+
+  pub fn get_height(&self) -> u64 {
+    self.height
+  }
+}
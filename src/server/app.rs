@@ -0,0 +1,172 @@
+use super::chain::Chain;
+use crate::merkle;
+use crate::{Block, BlockID, Transaction};
+use log::debug;
+use std::collections::{HashMap, VecDeque};
+
+// Blocks between two snapshots of the application, and snapshots kept to go
+// back before the blocks removed by a reorg without executing the chain
+// again.
+const SNAPSHOT_INTERVAL: u64 = 16;
+const MAX_SNAPSHOTS: usize = 4;
+
+// Replicated state machine executing the transactions of the committed
+// blocks. Every node runs the same blocks in the same order so the state
+// root returned after each block is the same everywhere.
+pub trait Application: Send + 'static {
+  // Checks a transaction before it enters the mempool. Blocks proposed by
+  // other nodes can still contain transactions this node would reject, so
+  // delivering them must not fail.
+  fn check_tx(&self, tx: &Transaction) -> bool;
+
+  // Executes the transactions of a block of the best branch in order.
+  fn deliver_block(&mut self, block: &Block);
+
+  // Ends the block being delivered and returns the root of the state.
+  fn commit(&mut self) -> merkle::Hash;
+
+  // Goes back to the initial state, given in the encoding of the application
+  // by the genesis. The best branch is then delivered again, which happens
+  // when the chain is loaded and after a reorg deeper than the snapshots.
+  fn reset(&mut self, state: &[u8]);
+
+  // Returns a copy of the application in its current state, which can run
+  // other blocks without changing this one.
+  fn snapshot(&self) -> Box<dyn Application>;
+
+  // Answers a query of a client about the last committed state.
+  fn query(&self, data: &[u8]) -> Vec<u8>;
+}
+
+// Drives the application over the best branch of the chain.
+pub struct Executor {
+  app: Box<dyn Application>,
+  // Initial state of the application defined by the genesis.
+  state: Vec<u8>,
+  // State root reached after each block of the best branch.
+  roots: HashMap<BlockID, merkle::Hash>,
+  // Copies of the application after some of the last blocks of the best
+  // branch, with their height and ID, from the oldest.
+  snapshots: VecDeque<(u64, BlockID, Box<dyn Application>)>,
+  // Copy of the application before the tip of the best branch, with the ID
+  // of the tip. The root of its state is in the header of the tip so the
+  // queries are answered with it.
+  committed: Option<(BlockID, Box<dyn Application>)>,
+}
+
+impl Executor {
+  // Creates the executor and brings the application to the tip of the chain.
+  pub fn new(app: impl Application, state: Vec<u8>, chain: &Chain) -> Self {
+    let mut executor = Executor {
+      app: Box::new(app),
+      state,
+      roots: HashMap::new(),
+      snapshots: VecDeque::new(),
+      committed: None,
+    };
+    executor.replay(chain);
+    executor
+  }
+
+  pub fn check_tx(&self, tx: &Transaction) -> bool {
+    self.app.check_tx(tx)
+  }
+
+  // Answers the query against the state whose root is in the header of the
+  // tip, and returns the ID of the tip with the answer. There is none before
+  // the first block.
+  pub fn query(&self, data: &[u8]) -> Option<(BlockID, Vec<u8>)> {
+    self.committed.as_ref().map(|(id, app)| (id.clone(), app.query(data)))
+  }
+
+  // Returns the state root reached after the given block of the best branch.
+  pub fn get_root(&self, id: &BlockID) -> Option<merkle::Hash> {
+    self.roots.get(id).cloned()
+  }
+
+  // Executes the changes of the best branch. The application can't undo
+  // blocks so it goes back to the last snapshot before the removed ones, or
+  // to the initial state when there is none.
+  pub fn apply(&mut self, chain: &Chain, removed: &[Block], added: &[Block]) {
+    let fork = match removed.first() {
+      Some(block) => block.get_height() - 1,
+      None => {
+        for block in added {
+          self.execute(block);
+        }
+        return;
+      }
+    };
+
+    // The snapshots of the removed blocks are dropped.
+    self.snapshots.retain(|(height, id, _)| {
+      *height <= fork && chain.get(*height).is_some_and(|b| b.hash() == *id)
+    });
+    let height = match self.snapshots.back() {
+      Some((height, _, app)) => {
+        self.app = app.snapshot();
+        *height
+      }
+      None => {
+        self.replay(chain);
+        return;
+      }
+    };
+
+    for block in (height + 1..).map_while(|h| chain.get(h)) {
+      self.execute(block);
+    }
+  }
+
+  // Returns the state root that would be reached after blocks following the
+  // tip of the chain, which are not committed yet. They run on a copy of the
+  // application so that its state stays at the tip.
+  pub fn speculate(&self, chain: &Chain, blocks: &[Block]) -> merkle::Hash {
+    let mut app = self.app.snapshot();
+    let mut root = self.roots[&chain.last().hash()];
+    for block in blocks {
+      app.deliver_block(block);
+      root = app.commit();
+    }
+    root
+  }
+
+  // Executes the best branch from the initial state.
+  fn replay(&mut self, chain: &Chain) {
+    self.app.reset(&self.state);
+    self.roots.clear();
+    self.snapshots.clear();
+    self.committed = None;
+
+    // The root of the initial state is the one after the genesis block.
+    let genesis = chain.get(0).unwrap();
+    let root = self.app.commit();
+    self.roots.insert(genesis.hash(), root);
+    self.keep_snapshot(genesis);
+
+    for block in (1..).map_while(|h| chain.get(h)) {
+      self.execute(block);
+    }
+  }
+
+  fn execute(&mut self, block: &Block) {
+    self.committed = Some((block.hash(), self.app.snapshot()));
+    self.app.deliver_block(block);
+    let root = self.app.commit();
+    debug!("block {} leads to state root {:02x?}", block.hash(), &root[..4]);
+
+    self.roots.insert(block.hash(), root);
+    self.keep_snapshot(block);
+  }
+
+  fn keep_snapshot(&mut self, block: &Block) {
+    if !block.get_height().is_multiple_of(SNAPSHOT_INTERVAL) {
+      return;
+    }
+
+    self.snapshots.push_back((block.get_height(), block.hash(), self.app.snapshot()));
+    if self.snapshots.len() > MAX_SNAPSHOTS {
+      self.snapshots.pop_front();
+    }
+  }
+}
@@ -0,0 +1,411 @@
+use super::mempool::Nonces;
+use super::store::Store;
+use crate::codec;
+use crate::crypto::PublicKey;
+use crate::{Block, BlockID, Genesis};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+
+// Encoded size of the blocks of an answer, leaving room for the message.
+const MAX_ANSWER_SIZE: usize = codec::MAX_SIZE as usize / 2;
+
+// Last nonce of the senders of a block, before and after the block.
+type NonceChanges = HashMap<PublicKey, (Option<u64>, u64)>;
+
+// Takes the blocks in order until the answer is full. The first block is
+// always taken as a block fits in a datagram on its own.
+pub fn take_answer<'a>(blocks: impl Iterator<Item = &'a Block>) -> Vec<Block> {
+  let mut size = 0;
+  let mut res = Vec::new();
+  for block in blocks {
+    size += codec::encode_unbounded(block).len();
+    if size > MAX_ANSWER_SIZE && !res.is_empty() {
+      break;
+    }
+    res.push(block.clone());
+  }
+
+  res
+}
+
+// Tree of the committed blocks starting with the genesis block. Conflicting
+// blocks can be committed at the same height, for instance when a view change
+// happens while a block is being acknowledged, so every block is kept and the
+// best branch is chosen with the same rule on every node:
+//  - the highest branch wins,
+//  - otherwise the branches are compared at the fork point where the block of
+//    the highest round wins, then the one with the smallest ID.
+pub struct Chain {
+  blocks: HashMap<BlockID, Block>,
+  // IDs of the blocks of the best branch indexed by height.
+  best: Vec<BlockID>,
+  // Validators flagged by the evidence of the branch ending with each block.
+  offenders: HashMap<BlockID, HashSet<usize>>,
+  // Last nonce of each sender on the best branch, and the changes made by
+  // each block to go from one branch to another.
+  nonces: Nonces,
+  changes: HashMap<BlockID, NonceChanges>,
+  // Log where the blocks are written before being inserted, if the chain
+  // must survive restarts.
+  store: Option<Store>,
+}
+
+impl Chain {
+  pub fn new(genesis: &Genesis) -> Self {
+    let genesis = Block::genesis(genesis);
+    let id = genesis.hash();
+
+    let mut blocks = HashMap::new();
+    blocks.insert(id.clone(), genesis);
+
+    let mut offenders = HashMap::new();
+    offenders.insert(id.clone(), HashSet::new());
+
+    let mut changes = HashMap::new();
+    changes.insert(id.clone(), NonceChanges::new());
+
+    Chain {
+      blocks,
+      best: vec![id],
+      offenders,
+      nonces: Nonces::new(),
+      changes,
+      store: None,
+    }
+  }
+
+  // Loads the chain from the block log at the given path, which is created
+  // if needed. The new blocks are then appended to it. The log must start
+  // with a child of the genesis block, otherwise it has been written by a
+  // node of another network.
+  pub fn open(path: &Path, genesis: &Genesis) -> io::Result<Self> {
+    let (store, blocks) = Store::open(path)?;
+
+    let mut chain = Chain::new(genesis);
+    if let Some(first) = blocks.first() {
+      if first.get_parent() != &chain.last().hash() {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidData,
+          format!(
+            "{} doesn't follow genesis {} of chain {}",
+            path.display(),
+            chain.last().hash(),
+            genesis.get_chain_id()
+          ),
+        ));
+      }
+    }
+
+    for block in blocks {
+      chain.insert(block)?;
+    }
+    chain.store = Some(store);
+
+    Ok(chain)
+  }
+
+  pub fn last(&self) -> &Block {
+    &self.blocks[self.best.last().unwrap()]
+  }
+
+  // Returns the block of the best branch at the given height.
+  pub fn get(&self, height: u64) -> Option<&Block> {
+    self.best.get(height as usize).map(|id| &self.blocks[id])
+  }
+
+  pub fn get_by_id(&self, id: &BlockID) -> Option<&Block> {
+    self.blocks.get(id)
+  }
+
+  // Returns the validators flagged on the branch ending with the given block.
+  pub fn get_offenders(&self, id: &BlockID) -> Option<&HashSet<usize>> {
+    self.offenders.get(id)
+  }
+
+  // Returns the last nonce of each sender on the best branch.
+  pub fn get_nonces(&self) -> &Nonces {
+    &self.nonces
+  }
+
+  // Checks that the nonces of the transactions of a block following a known
+  // one grow for each sender, so that none of them is a replay.
+  pub fn verify_nonces(&self, block: &Block) -> bool {
+    if !self.contains(block.get_parent()) {
+      return false;
+    }
+    let nonces = self.get_branch_nonces(block.get_parent());
+
+    let mut seen = HashMap::new();
+    block.get_txs().iter().all(|tx| {
+      let last = seen.insert(tx.get_sender(), tx.get_nonce());
+      let last = last.or_else(|| nonces.get(tx.get_sender()).copied());
+      last.is_none_or(|n| tx.get_nonce() > n)
+    })
+  }
+
+  // Returns the last nonce of each sender on the branch ending with the given
+  // block. The nonces of the best branch are brought back to the fork point
+  // before the changes of the other branch are made, which only happens for
+  // blocks outside of the best branch.
+  fn get_branch_nonces(&self, id: &BlockID) -> Cow<'_, Nonces> {
+    if id == self.best.last().unwrap() {
+      return Cow::Borrowed(&self.nonces);
+    }
+
+    let mut branch = Vec::new();
+    let mut cursor = id;
+    while !self.is_best(cursor) {
+      branch.push(cursor);
+      cursor = self.blocks[cursor].get_parent();
+    }
+
+    let fork = self.blocks[cursor].get_height() as usize;
+    let mut nonces = self.nonces.clone();
+    for id in self.best[fork + 1..].iter().rev() {
+      self.undo_nonces(&mut nonces, id);
+    }
+    for id in branch.into_iter().rev() {
+      self.redo_nonces(&mut nonces, id);
+    }
+    Cow::Owned(nonces)
+  }
+
+  fn undo_nonces(&self, nonces: &mut Nonces, id: &BlockID) {
+    for (sender, (before, _)) in &self.changes[id] {
+      match before {
+        Some(nonce) => nonces.insert(sender.clone(), *nonce),
+        None => nonces.remove(sender),
+      };
+    }
+  }
+
+  fn redo_nonces(&self, nonces: &mut Nonces, id: &BlockID) {
+    for (sender, (_, after)) in &self.changes[id] {
+      nonces.insert(sender.clone(), *after);
+    }
+  }
+
+  // Returns true when the block is known, even outside of the best branch.
+  pub fn contains(&self, id: &BlockID) -> bool {
+    self.blocks.contains_key(id)
+  }
+
+  // Inserts a block following a known one. It returns the blocks removed from
+  // the best branch and the ones added to it, both ordered by height, which
+  // are empty when the block lands on another branch. Unknown parents and
+  // known blocks are ignored.
+  pub fn insert(&mut self, block: Block) -> io::Result<Option<(Vec<Block>, Vec<Block>)>> {
+    let id = block.hash();
+    if self.contains(&id) || !self.contains(block.get_parent()) {
+      return Ok(None);
+    }
+
+    if let Some(store) = &mut self.store {
+      store.append(&block)?;
+    }
+
+    let mut offenders = self.offenders[block.get_parent()].clone();
+    offenders.extend(block.get_evidence().iter().map(|e| e.get_offender()));
+    self.offenders.insert(id.clone(), offenders);
+
+    let changes = {
+      let nonces = self.get_branch_nonces(block.get_parent());
+      let mut changes = NonceChanges::new();
+      for tx in block.get_txs() {
+        let before = nonces.get(tx.get_sender()).copied();
+        let change = changes.entry(tx.get_sender().clone()).or_insert((before, before.unwrap_or_default()));
+        change.1 = change.1.max(tx.get_nonce());
+      }
+      changes
+    };
+    self.changes.insert(id.clone(), changes);
+    self.blocks.insert(id.clone(), block);
+
+    if !self.is_better(&id, self.best.last().unwrap()) {
+      return Ok(Some((vec![], vec![])));
+    }
+
+    // Walk back the new branch up to the fork point.
+    let mut added = Vec::new();
+    let mut cursor = id;
+    while !self.is_best(&cursor) {
+      let parent = self.blocks[&cursor].get_parent().clone();
+      added.push(cursor);
+      cursor = parent;
+    }
+    added.reverse();
+
+    let fork = self.blocks[&cursor].get_height() as usize;
+    let removed = self.best.split_off(fork + 1);
+    self.best.extend(added.iter().cloned());
+
+    let mut nonces = std::mem::take(&mut self.nonces);
+    for id in removed.iter().rev() {
+      self.undo_nonces(&mut nonces, id);
+    }
+    for id in &added {
+      self.redo_nonces(&mut nonces, id);
+    }
+    self.nonces = nonces;
+
+    Ok(Some((self.collect(&removed), self.collect(&added))))
+  }
+
+  fn is_best(&self, id: &BlockID) -> bool {
+    let height = self.blocks[id].get_height() as usize;
+    self.best.get(height) == Some(id)
+  }
+
+  // Returns true when the branch ending with the first block is preferred over
+  // the one ending with the second.
+  fn is_better(&self, a: &BlockID, b: &BlockID) -> bool {
+    let (mut a, mut b) = (&self.blocks[a], &self.blocks[b]);
+    if a.get_height() != b.get_height() {
+      return a.get_height() > b.get_height();
+    }
+
+    while a.get_parent() != b.get_parent() {
+      a = &self.blocks[a.get_parent()];
+      b = &self.blocks[b.get_parent()];
+    }
+
+    (a.get_round(), Reverse(a.hash())) > (b.get_round(), Reverse(b.hash()))
+  }
+
+  fn collect(&self, ids: &[BlockID]) -> Vec<Block> {
+    ids.iter().map(|id| self.blocks[id].clone()).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::crypto::KeyPair;
+  use crate::{Seed, Transaction};
+  use std::net::SocketAddr;
+
+  fn new_chain() -> Chain {
+    Chain::new(&Genesis::new("test".to_string(), Vec::new(), Seed::default(), Vec::new()))
+  }
+
+  fn child(parent: &Block, round: u64, txs: Vec<Transaction>) -> Block {
+    let leader = SocketAddr::from(([127, 0, 0, 1], 3000));
+    parent.next(&KeyPair::generate().unwrap(), leader, round, txs)
+  }
+
+  fn ids(blocks: &[Block]) -> Vec<BlockID> {
+    blocks.iter().map(Block::hash).collect()
+  }
+
+  #[test]
+  fn prefers_the_highest_round_then_the_smallest_id() {
+    let mut chain = new_chain();
+    let genesis = chain.last().clone();
+
+    let low = child(&genesis, 1, Vec::new());
+    let high = child(&genesis, 2, Vec::new());
+    assert_eq!(ids(&chain.insert(low.clone()).unwrap().unwrap().1), vec![low.hash()]);
+    let (removed, added) = chain.insert(high.clone()).unwrap().unwrap();
+    assert_eq!((ids(&removed), ids(&added)), (vec![low.hash()], vec![high.hash()]));
+
+    // A block of a lower round stays aside.
+    let (removed, added) = chain.insert(child(&genesis, 0, Vec::new())).unwrap().unwrap();
+    assert!(removed.is_empty() && added.is_empty());
+
+    // In the same round, the smallest ID wins whatever the order.
+    let other = child(&genesis, 2, Vec::new());
+    chain.insert(other.clone()).unwrap();
+    assert_eq!(chain.last().hash(), high.hash().min(other.hash()));
+    assert_eq!(chain.last().get_height(), 1);
+  }
+
+  #[test]
+  fn switches_to_a_longer_branch() {
+    let mut chain = new_chain();
+    let genesis = chain.last().clone();
+
+    let a1 = child(&genesis, 2, Vec::new());
+    let a2 = child(&a1, 0, Vec::new());
+    chain.insert(a1.clone()).unwrap();
+    chain.insert(a2.clone()).unwrap();
+
+    // The side branch forks from the genesis block and overtakes the best
+    // one at height 3.
+    let b1 = child(&genesis, 1, Vec::new());
+    let b2 = child(&b1, 0, Vec::new());
+    let b3 = child(&b2, 0, Vec::new());
+    for block in [&b1, &b2] {
+      let (removed, added) = chain.insert(block.clone()).unwrap().unwrap();
+      assert!(removed.is_empty() && added.is_empty());
+    }
+    let (removed, added) = chain.insert(b3.clone()).unwrap().unwrap();
+
+    assert_eq!(ids(&removed), vec![a1.hash(), a2.hash()]);
+    assert_eq!(ids(&added), vec![b1.hash(), b2.hash(), b3.hash()]);
+    assert_eq!(chain.last().hash(), b3.hash());
+    assert_eq!(chain.get(1).unwrap().hash(), b1.hash());
+    assert!(chain.contains(&a2.hash()));
+  }
+
+  #[test]
+  fn ignores_unknown_parents_and_known_blocks() {
+    let mut chain = new_chain();
+    let genesis = chain.last().clone();
+
+    let orphan = child(&child(&genesis, 0, Vec::new()), 0, Vec::new());
+    assert!(chain.insert(orphan.clone()).unwrap().is_none());
+    assert!(!chain.contains(&orphan.hash()));
+    assert_eq!(chain.last().hash(), genesis.hash());
+
+    let block = child(&genesis, 0, Vec::new());
+    assert!(chain.insert(block.clone()).unwrap().is_some());
+    assert!(chain.insert(block).unwrap().is_none());
+  }
+  #[test]
+  fn rejects_replayed_nonces() {
+    let mut chain = new_chain();
+    let genesis = chain.last().clone();
+    let sender = KeyPair::generate().unwrap();
+    let tx = |nonce| Transaction::new(nonce, Vec::new(), &sender);
+
+    let block = child(&genesis, 0, vec![tx(1), tx(3)]);
+    assert!(chain.verify_nonces(&block));
+    chain.insert(block.clone()).unwrap();
+    assert_eq!(chain.get_nonces().get(&sender.public_key()), Some(&3));
+
+    assert!(!chain.verify_nonces(&child(&block, 0, vec![tx(3)])));
+    assert!(!chain.verify_nonces(&child(&block, 0, vec![tx(2)])));
+    assert!(chain.verify_nonces(&child(&block, 0, vec![tx(4)])));
+
+    // The nonces must grow inside a block as well.
+    assert!(!chain.verify_nonces(&child(&block, 0, vec![tx(5), tx(5)])));
+    assert!(!chain.verify_nonces(&child(&block, 0, vec![tx(6), tx(5)])));
+  }
+
+  #[test]
+  fn follows_the_nonces_of_each_branch() {
+    let mut chain = new_chain();
+    let genesis = chain.last().clone();
+    let sender = KeyPair::generate().unwrap();
+    let tx = |nonce| Transaction::new(nonce, Vec::new(), &sender);
+
+    let a1 = child(&genesis, 2, vec![tx(5)]);
+    chain.insert(a1).unwrap();
+
+    // The nonces used on the best branch are still free on the side one.
+    let b1 = child(&genesis, 1, vec![tx(1)]);
+    chain.insert(b1.clone()).unwrap();
+    let b2 = child(&b1, 0, vec![tx(2)]);
+    assert!(chain.verify_nonces(&b2));
+    assert!(!chain.verify_nonces(&child(&b1, 0, vec![tx(1)])));
+    assert_eq!(chain.get_nonces().get(&sender.public_key()), Some(&5));
+
+    chain.insert(b2.clone()).unwrap();
+    assert_eq!(chain.last().hash(), b2.hash());
+    assert_eq!(chain.get_nonces().get(&sender.public_key()), Some(&2));
+    assert!(chain.verify_nonces(&child(&b2, 0, vec![tx(3)])));
+  }
+}
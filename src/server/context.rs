@@ -1,48 +1,96 @@
+use super::app::{Application, Executor};
+use super::chain::{self, Chain};
+use super::limiter::Limiter;
+use super::mempool::Mempool;
 use super::service::Service;
-use crate::{Block, Event, Message};
+use super::submitters::Submitters;
+use super::{Limits, Notification, Timeouts};
+use crate::codec;
+use crate::crypto::{KeyPair, PublicKey};
+use crate::merkle;
+use crate::peer;
+use crate::{Block, BlockID, ChainQuery, Event, Hello, Message, Peer, Receipt, Transaction};
 use mio::{net::UdpSocket, Events, Poll, PollOpt, Ready, Token};
-use log::{error, trace};
+use log::{error, info, trace};
+use std::collections::HashSet;
 use std::io;
 use std::net::SocketAddr;
 use std::sync::mpsc::Sender;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
-const WAIT_TIMEOUT: Option<Duration> = Some(Duration::from_millis(100));
+// Interval between two hellos to the validators that haven't answered.
+const HELLO_INTERVAL: Duration = Duration::from_secs(1);
+
+// Bytes per second of the blocks sent to each host querying the chain.
+const CHAIN_ANSWER_RATE: u64 = 256 * 1024;
 
 pub struct Context {
   socket: Arc<UdpSocket>,
   poll: Poll,
   addr: SocketAddr,
-  peers: Vec<SocketAddr>,
-  tx: Mutex<Sender<Block>>,
+  keypair: KeyPair,
+  peers: Vec<Peer>,
+  quorum: u64,
+  // ID of the genesis block exchanged in the handshake with the validators.
+  genesis: BlockID,
+  // Validators that run the same genesis, whose events are accepted.
+  greeted: Mutex<HashSet<usize>>,
+  last_hello: Mutex<Option<Instant>>,
+  chain: Mutex<Chain>,
+  mempool: Mutex<Mempool>,
+  executor: Option<Mutex<Executor>>,
+  submitters: Mutex<Submitters>,
+  // Budget of the answers to the chain queries of each host.
+  limiter: Mutex<Limiter>,
+  tx: Mutex<Sender<Notification>>,
   event_service: Option<Box<dyn Service>>,
-  message_queue: Mutex<Vec<(Event, SocketAddr)>>,
+  message_queue: Mutex<Vec<(Message, SocketAddr)>>,
+  poll_timeout: Duration,
+  poll_events: usize,
 }
 
 const TOKEN: Token = Token(0);
 
 impl Context {
-  pub fn new(addr: SocketAddr, peers: Vec<SocketAddr>, tx: Sender<Block>) -> io::Result<Self> {
+  pub fn new(
+    addr: SocketAddr,
+    keypair: KeyPair,
+    peers: Vec<Peer>,
+    quorum: u64,
+    chain: Chain,
+    mempool: Mempool,
+    tx: Sender<Notification>,
+  ) -> io::Result<Self> {
     let socket = Arc::new(UdpSocket::bind(&addr)?);
 
-    // Socket poll to get readable and writable events from the OS.
+    // Socket poll to get readable events from the OS.
     let poll = Poll::new()?;
-    poll.register(
-      &socket,
-      TOKEN,
-      Ready::readable() | Ready::writable(),
-      PollOpt::edge(),
-    )?;
+    poll.register(&socket, TOKEN, Ready::readable(), PollOpt::edge())?;
+
+    let genesis = chain.get(0).unwrap().hash();
+    let greeted = peers.iter().position(|p| p.get_addr() == &addr).into_iter().collect();
 
     Ok(Context {
       socket,
       poll,
       tx: Mutex::new(tx),
       addr,
+      keypair,
       peers,
+      quorum,
+      genesis,
+      greeted: Mutex::new(greeted),
+      last_hello: Mutex::new(None),
+      chain: Mutex::new(chain),
+      mempool: Mutex::new(mempool),
+      executor: None,
+      submitters: Mutex::new(Submitters::new()),
+      limiter: Mutex::new(Limiter::new(CHAIN_ANSWER_RATE)),
       event_service: None,
       message_queue: Mutex::new(Vec::new()),
+      poll_timeout: Timeouts::default().poll,
+      poll_events: Limits::default().poll_events,
     })
   }
 
@@ -50,46 +98,241 @@ impl Context {
     &self.addr
   }
 
-  pub fn get_peers(&self) -> &Vec<SocketAddr> {
+  pub fn get_keypair(&self) -> &KeyPair {
+    &self.keypair
+  }
+
+  pub fn get_peers(&self) -> &Vec<Peer> {
     &self.peers
   }
 
+  // The lock must be released before announcing a block.
+  pub fn lock_chain(&self) -> MutexGuard<'_, Chain> {
+    self.chain.lock().unwrap()
+  }
+
+  // The chain must be locked first when both are needed.
+  pub fn lock_mempool(&self) -> MutexGuard<'_, Mempool> {
+    self.mempool.lock().unwrap()
+  }
+
+  // Adds a transaction to the mempool after checking its signature, that its
+  // nonce is higher than the last one of the sender in the chain and that the
+  // application accepts it. It returns false when the transaction is invalid
+  // or already pending, otherwise the client that sent it is remembered to
+  // get the receipt.
+  pub fn add_transaction(&self, tx: Transaction, from: &SocketAddr) -> bool {
+    let chain = self.lock_chain();
+    let last = chain.get_nonces().get(tx.get_sender());
+    if !tx.verify() || last.is_some_and(|&n| tx.get_nonce() <= n) {
+      return false;
+    }
+    if self.executor.as_ref().is_some_and(|e| !e.lock().unwrap().check_tx(&tx)) {
+      return false;
+    }
+
+    // The node receiving the transaction from the client sends the receipt,
+    // not the ones it is forwarded to.
+    let id = tx.hash();
+    if !self.lock_mempool().add(tx) {
+      return false;
+    }
+    if self.get_public_key(from).is_none() {
+      self.submitters.lock().unwrap().add(id, *from);
+    }
+    true
+  }
+
+  pub fn get_quorum(&self) -> u64 {
+    self.quorum
+  }
+
+  // Returns the voting power of the validators at the given distinct indexes.
+  pub fn get_power(&self, indexes: impl IntoIterator<Item = usize>) -> u64 {
+    peer::power(&self.peers, indexes)
+  }
+
+  pub fn get_index(&self) -> usize {
+    self.peers.iter().position(|p| p.get_addr() == &self.addr).unwrap()
+  }
+
+  pub fn get_peer_index(&self, addr: &SocketAddr) -> Option<usize> {
+    self.peers.iter().position(|p| p.get_addr() == addr)
+  }
+
+  pub fn get_public_key(&self, addr: &SocketAddr) -> Option<&PublicKey> {
+    self
+      .peers
+      .iter()
+      .find(|p| p.get_addr() == addr)
+      .map(|p| p.get_public_key())
+  }
+
+  // Sets the longest wait for a message before the timers are checked, and
+  // the number of readiness events taken from each poll.
+  pub fn set_poll(&mut self, timeout: Duration, events: usize) {
+    self.poll_timeout = timeout;
+    self.poll_events = events;
+  }
+
   pub fn register_event_handler(&mut self, service: impl Service) -> io::Result<()> {
     self.event_service = Some(Box::new(service));
 
     Ok(())
   }
 
+  // Returns the state root reached by the application after the given block
+  // of the best branch, which is expected in the header of the next block.
+  pub fn get_state_root(&self, id: &BlockID) -> Option<merkle::Hash> {
+    match &self.executor {
+      Some(executor) => executor.lock().unwrap().get_root(id),
+      None => Some(Default::default()),
+    }
+  }
+
+  // Returns the state root that would be reached after blocks following the
+  // tip of the chain which are not committed yet.
+  pub fn speculate_state_root(&self, chain: &Chain, blocks: &[Block]) -> merkle::Hash {
+    match &self.executor {
+      Some(executor) => executor.lock().unwrap().speculate(chain, blocks),
+      None => Default::default(),
+    }
+  }
+
+  // The application starts from the given state of the genesis and executes
+  // the chain loaded so far before the new blocks.
+  pub fn register_application(&mut self, app: impl Application, state: Vec<u8>) -> io::Result<()> {
+    let executor = Executor::new(app, state, &self.chain.lock().unwrap());
+    self.executor = Some(Mutex::new(executor));
+
+    Ok(())
+  }
+
   pub fn next(&self) {
-    let mut events = Events::with_capacity(128);
-    self.poll.poll(&mut events, WAIT_TIMEOUT).unwrap();
+    let mut events = Events::with_capacity(self.poll_events);
+    self.poll.poll(&mut events, Some(self.poll_timeout)).unwrap();
 
     for event in events {
-      if event.token() == TOKEN {
-        if event.readiness().is_writable() {
-          self.send_next();
+      if event.token() == TOKEN && event.readiness().is_readable() {
+        self.receive_all();
+      }
+    }
+
+    if let Some(h) = &self.event_service {
+      if let Err(e) = h.process_tick(self) {
+        error!("Error when processing a tick: {:?}", e);
+      }
+    }
+    self.greet();
+
+    // Messages produced by the handlers are sent right away as the socket
+    // won't be notified again while it stays writable.
+    self.send_all();
+  }
+
+  fn receive_all(&self) {
+    let mut buf = vec![0; codec::MAX_SIZE as usize];
+
+    loop {
+      let (size, src) = match self.socket.recv_from(&mut buf) {
+        Ok(res) => res,
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+        Err(e) => {
+          error!("{} failed to receive: {:?}", self.addr, e);
+          return;
+        }
+      };
+
+      let msg: Message = match codec::decode(&buf[..size]) {
+        Ok(msg) => msg,
+        Err(e) => {
+          error!("{} got a malformed message from {}: {:?}", self.addr, src, e);
+          continue;
         }
+      };
 
-        if event.readiness().is_readable() {
-          let mut buf = [0; 1024];
-          let (size, src) = self.socket.recv_from(&mut buf).unwrap();
-
-          let msg: Message = serde_json::from_slice(&buf[..size]).unwrap();
-
-          match msg {
-            Message::Event(evt) => {
-              if let Err(e) = self.handle_event(evt, &src) {
-                error!("Error when processing an event: {:?}", e);
-              }
-            }
-            Message::Request(data) => {
-              if let Err(e) = self.handle_request(data) {
-                error!("Error when processing a request: {:?}", e);
-              }
-            }
-          };
+      match msg {
+        Message::Event(_) if !self.is_greeted(&src) => {
+          trace!("{} ignores an event from {} which isn't a greeted validator", self.addr, src)
         }
+        Message::Event(evt) => {
+          if let Err(e) = self.handle_event(evt, &src) {
+            error!("Error when processing an event: {:?}", e);
+          }
+        }
+        Message::Request(tx) => {
+          if let Err(e) = self.handle_request(tx, &src) {
+            error!("Error when processing a request: {:?}", e);
+          }
+        }
+        Message::Hello(hello) => self.handle_hello(hello, &src),
+        Message::Query(data) => self.handle_query(&data, &src),
+        Message::GetChain(query) => self.handle_chain_query(query, &src),
+        Message::QueryResult(..) | Message::Receipt(_) | Message::ChainResult(_) => {
+          trace!("{} ignores an answer from {}", self.addr, src)
+        }
+      };
+    }
+  }
+
+  // Returns true for the validators that have shown that they run the same
+  // genesis. Events are only exchanged between validators, so that a spoofed
+  // sender can't make a node send blocks to another host.
+  fn is_greeted(&self, addr: &SocketAddr) -> bool {
+    match self.get_peer_index(addr) {
+      Some(index) => self.greeted.lock().unwrap().contains(&index),
+      None => false,
+    }
+  }
+
+  // Sends a hello to the validators that haven't answered yet, at most once
+  // per interval as they may not be running.
+  fn greet(&self) {
+    let mut last = self.last_hello.lock().unwrap();
+    if last.is_some_and(|t| t.elapsed() < HELLO_INTERVAL) {
+      return;
+    }
+    *last = Some(Instant::now());
+
+    let greeted = self.greeted.lock().unwrap();
+    let hello = Message::Hello(Hello::new(self.genesis.clone(), false, &self.keypair));
+    for (index, peer) in self.peers.iter().enumerate() {
+      if !greeted.contains(&index) {
+        self.send_message(&hello, peer.get_addr());
+      }
+    }
+  }
+
+  // Accepts the events of a validator once it shows that it runs the same
+  // genesis, and answers its hello so that it accepts the events of this
+  // node. A validator that restarts with another genesis is dropped.
+  fn handle_hello(&self, hello: Hello, from: &SocketAddr) {
+    let index = match self.get_peer_index(from) {
+      Some(index) if hello.verify(self.peers[index].get_public_key()) => index,
+      Some(_) => {
+        error!("{} got a hello with an invalid signature from {}", self.addr, from);
+        return;
       }
+      None => return,
+    };
+
+    let genesis = hello.get_genesis();
+    {
+      let mut greeted = self.greeted.lock().unwrap();
+      if genesis != &self.genesis {
+        greeted.remove(&index);
+        error!(
+          "{} rejects {} which runs genesis {} instead of {}",
+          self.addr, from, genesis, self.genesis
+        );
+      } else if greeted.insert(index) {
+        info!("{} completed the handshake with {}", self.addr, from);
+      }
+    }
+
+    if !hello.is_answer() {
+      let hello = Hello::new(self.genesis.clone(), true, &self.keypair);
+      self.send_message(&Message::Hello(hello), from);
     }
   }
 
@@ -102,40 +345,169 @@ impl Context {
     Ok(())
   }
 
-  fn handle_request(&self, data: Vec<u8>) -> io::Result<()> {
+  fn handle_request(&self, tx: Transaction, from: &SocketAddr) -> io::Result<()> {
     if let Some(h) = &self.event_service {
-      h.process_request(self, data)?;
+      h.process_request(self, tx, from)?;
     }
 
     Ok(())
   }
 
-  pub fn send(&self, evt: &Event, addr: &SocketAddr) {
-    let mut queue = self.message_queue.lock().unwrap();
+  // Tells the clients that submitted transactions of the blocks in which
+  // block they have been committed.
+  fn send_receipts(&self, blocks: &[Block]) {
+    let mut submitters = self.submitters.lock().unwrap();
+    for block in blocks {
+      let addrs: Vec<_> = block.get_txs().iter().map(|tx| submitters.take(&tx.hash())).collect();
+      if addrs.iter().all(Option::is_none) {
+        continue;
+      }
 
-    queue.push((evt.clone(), addr.clone()));
+      let proofs = block.prove_all();
+      for ((tx, addr), proof) in block.get_txs().iter().zip(addrs).zip(proofs) {
+        if let Some(addr) = addr {
+          let receipt = Receipt::new(tx.hash(), block.hash(), block.get_height(), proof);
+          self.send_message(&Message::Receipt(receipt), &addr);
+        }
+      }
+    }
   }
 
-  pub fn propagate(&self, evt: &Event) {
-    let idx = self.peers.iter().position(|&p| p == self.addr).unwrap();
-    let idx = (idx + 1) % self.peers.len();
-    let to = self.peers.get(idx).unwrap();
+  // Answers the query of a client with the state whose root is in the header
+  // of the tip of the chain, so that the answer can be checked against it.
+  fn handle_query(&self, data: &[u8], from: &SocketAddr) {
+    let res = match &self.executor {
+      Some(executor) => executor.lock().unwrap().query(data),
+      None => return,
+    };
 
-    self.send(evt, to);
+    match res {
+      Some((id, res)) => self.send_message(&Message::QueryResult(id, res), from),
+      None => trace!("{} has no committed state to answer a query from {}", self.addr, from),
+    };
   }
 
-  pub fn announce_block(&self, block: &Block) {
-    self.tx.lock().unwrap().send(block.clone()).unwrap();
+  // Answers the query of a client with the blocks of the chain it asks for,
+  // within the budget of its host.
+  fn handle_chain_query(&self, query: ChainQuery, from: &SocketAddr) {
+    let blocks = {
+      let chain = self.lock_chain();
+      match query {
+        ChainQuery::Tip => vec![chain.last().clone()],
+        ChainQuery::Height(height) => chain.get(height).cloned().into_iter().collect(),
+        ChainQuery::Block(id) => chain.get_by_id(&id).cloned().into_iter().collect(),
+        ChainQuery::Range(start, limit) => {
+          let blocks = (start..).map_while(|h| chain.get(h)).take(limit as usize);
+          chain::take_answer(blocks)
+        }
+      }
+    };
+
+    let res = Message::ChainResult(blocks);
+    let size = codec::encode_unbounded(&res).len();
+    if !self.limiter.lock().unwrap().take(from.ip(), size, Instant::now()) {
+      trace!("{} drops a chain query from {} over its budget", self.addr, from);
+      return;
+    }
+    self.send_message(&res, from);
   }
 
-  fn send_next(&self) {
+  pub fn send(&self, evt: &Event, addr: &SocketAddr) {
+    self.send_message(&Message::Event(evt.clone()), addr);
+  }
+
+  pub fn send_message(&self, msg: &Message, addr: &SocketAddr) {
     let mut queue = self.message_queue.lock().unwrap();
-    match queue.pop() {
-      Some((evt, to)) => {
-        let buf = serde_json::to_vec(&Message::Event(evt)).unwrap();
-        self.socket.send_to(&buf, &to).unwrap();
-      },
-      None => (),
+
+    queue.push((msg.clone(), *addr));
+  }
+
+  // Sends the event to every other peer.
+  pub fn broadcast(&self, evt: &Event) {
+    for peer in &self.peers {
+      if peer.get_addr() != &self.addr {
+        self.send(evt, peer.get_addr());
+      }
+    }
+  }
+
+  // Inserts a committed block in the chain, executes the changes of the best
+  // branch and notifies the subscribers. The transactions of the blocks
+  // leaving the best branch go back to the mempool while the ones of the
+  // blocks joining it are removed. It returns true when the block is the new
+  // tip of the chain.
+  pub fn announce_block(&self, block: &Block) -> bool {
+    let res = {
+      let mut chain = self.lock_chain();
+      let res = chain.insert(block.clone());
+      if let (Ok(Some((removed, added))), Some(executor)) = (&res, &self.executor) {
+        executor.lock().unwrap().apply(&chain, removed, added);
+      }
+      res
     };
+    let (removed, added) = match res {
+      Ok(Some(res)) => res,
+      Ok(None) => {
+        trace!("{} ignores block {} with an unknown parent", self.addr, block.hash());
+        return false;
+      }
+      Err(e) => {
+        error!("{} failed to store block {}: {:?}", self.addr, block.hash(), e);
+        return false;
+      }
+    };
+
+    {
+      let mut mempool = self.mempool.lock().unwrap();
+      for block in &removed {
+        mempool.revert(block.get_txs());
+      }
+      for block in &added {
+        mempool.commit(block.get_txs());
+      }
+    }
+    self.send_receipts(&added);
+
+    let tx = self.tx.lock().unwrap();
+    if added.is_empty() {
+      info!(
+        "{} detected a fork at height {} and keeps block {} aside",
+        self.addr,
+        block.get_height(),
+        block.hash()
+      );
+      false
+    } else if removed.is_empty() {
+      for block in added {
+        tx.send(Notification::Block(block)).unwrap();
+      }
+      true
+    } else {
+      info!(
+        "{} switches to the branch of block {} and drops {} blocks",
+        self.addr,
+        block.hash(),
+        removed.len()
+      );
+      tx.send(Notification::Reorg { removed, added }).unwrap();
+      true
+    }
+  }
+
+  fn send_all(&self) {
+    let mut queue = self.message_queue.lock().unwrap();
+
+    for (msg, to) in queue.drain(..) {
+      let buf = match codec::encode(&msg) {
+        Ok(buf) => buf,
+        Err(e) => {
+          error!("{} drops a message to {} that doesn't fit in a datagram: {}", self.addr, to, e);
+          continue;
+        }
+      };
+      if let Err(e) = self.socket.send_to(&buf, &to) {
+        error!("{} failed to send to {}: {:?}", self.addr, to, e);
+      }
+    }
   }
 }
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+// Hosts remembered, after which the ones with a full budget are forgotten.
+const MAX_HOSTS: usize = 10_000;
+
+// Bytes of answers that can be sent to each host, refilled at a constant rate
+// up to a second of it, so that queries with a spoofed address can't make a
+// node flood another host.
+pub struct Limiter {
+  // Bytes per second.
+  rate: u64,
+  // Budget left to each host at the time of its last answer.
+  hosts: HashMap<IpAddr, (u64, Instant)>,
+}
+
+impl Limiter {
+  pub fn new(rate: u64) -> Self {
+    Limiter {
+      rate,
+      hosts: HashMap::new(),
+    }
+  }
+
+  // Takes the size of an answer from the budget of the host, and returns
+  // false when it is exhausted.
+  pub fn take(&mut self, host: IpAddr, size: usize, now: Instant) -> bool {
+    if self.hosts.len() >= MAX_HOSTS && !self.hosts.contains_key(&host) {
+      let rate = self.rate;
+      self.hosts.retain(|_, &mut (budget, time)| refill(budget, time, now, rate) < rate);
+      if self.hosts.len() >= MAX_HOSTS {
+        return false;
+      }
+    }
+
+    let (budget, time) = self.hosts.entry(host).or_insert((self.rate, now));
+    let available = refill(*budget, *time, now, self.rate);
+    *time = now;
+    match available.checked_sub(size as u64) {
+      Some(left) => {
+        *budget = left;
+        true
+      }
+      None => {
+        *budget = available;
+        false
+      }
+    }
+  }
+}
+
+fn refill(budget: u64, time: Instant, now: Instant, rate: u64) -> u64 {
+  let elapsed = now.saturating_duration_since(time).as_millis() as u64;
+  budget.saturating_add(rate.saturating_mul(elapsed) / 1000).min(rate)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  #[test]
+  fn refills_the_budget_of_each_host() {
+    let mut limiter = Limiter::new(1000);
+    let (a, b) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+    let now = Instant::now();
+
+    assert!(limiter.take(a, 600, now));
+    assert!(!limiter.take(a, 600, now));
+    assert!(limiter.take(b, 600, now));
+
+    // Half of the rate comes back after half a second, up to a full second.
+    let later = now + Duration::from_millis(500);
+    assert!(limiter.take(a, 900, later));
+    assert!(!limiter.take(a, 1, later));
+    assert!(!limiter.take(a, 1001, later + Duration::from_secs(10)));
+    assert!(limiter.take(a, 1000, later + Duration::from_secs(10)));
+  }
+}
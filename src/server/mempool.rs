@@ -0,0 +1,320 @@
+use super::{BlockPolicy, Limits};
+use crate::codec;
+use crate::crypto::PublicKey;
+use crate::{Transaction, TxID};
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+// Upper bound of the total size of the transactions of a block, leaving room
+// for the rest of the block in a datagram.
+pub const MAX_BLOCK_SIZE: usize = 32 * 1024;
+
+// Encoded size of the largest vote of a validator in the certificate sent
+// with a block, which is a prepare vote, and upper bound of the rest of a
+// block or proposal besides its transactions and certificate.
+pub const VOTE_SIZE: usize = 128;
+pub const BLOCK_OVERHEAD: usize = 1024;
+
+// Last nonce used by each sender.
+pub type Nonces = HashMap<PublicKey, u64>;
+
+fn size(tx: &Transaction) -> usize {
+  codec::encode_unbounded(tx).len()
+}
+
+// Transactions waiting to be included in a block, keyed by their ID so that a
+// transaction submitted several times is only kept once. Transactions are
+// checked against the chain before they enter the pool, and the ones whose
+// nonce has been used by a committed transaction are dropped.
+pub struct Mempool {
+  policy: BlockPolicy,
+  // Limits of the pool after which the oldest transactions are evicted.
+  max_txs: usize,
+  max_bytes: usize,
+  txs: HashMap<TxID, Transaction>,
+  // IDs of the pending transactions from the oldest.
+  order: VecDeque<TxID>,
+  size: usize,
+  // Time of the last committed block.
+  last_block: Instant,
+}
+
+impl Mempool {
+  pub fn new(policy: BlockPolicy, limits: &Limits) -> Self {
+    Mempool {
+      policy,
+      max_txs: limits.mempool_txs,
+      max_bytes: limits.mempool_bytes,
+      txs: HashMap::new(),
+      order: VecDeque::new(),
+      size: 0,
+      last_block: Instant::now(),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.txs.is_empty()
+  }
+
+  // Returns true when the pending transactions fill a block, or when they
+  // have waited long enough since the last block.
+  pub fn is_ready(&self) -> bool {
+    !self.is_empty()
+      && (self.txs.len() >= self.policy.max_txs
+        || self.size >= self.policy.max_bytes
+        || self.last_block.elapsed() >= self.policy.interval)
+  }
+
+  // Adds the transaction unless it is known or too large to fit in a block.
+  // It returns true when the transaction is new.
+  pub fn add(&mut self, tx: Transaction) -> bool {
+    let id = tx.hash();
+    let len = size(&tx);
+    if len > self.policy.max_bytes || self.txs.contains_key(&id) {
+      return false;
+    }
+
+    self.size += len;
+    self.txs.insert(id.clone(), tx);
+    self.order.push_back(id);
+
+    while self.txs.len() > self.max_txs || self.size > self.max_bytes {
+      let oldest = self.order.pop_front().unwrap();
+      if let Some(tx) = self.txs.remove(&oldest) {
+        self.size -= size(&tx);
+      }
+    }
+
+    true
+  }
+
+  // Returns the transactions of the next block from the oldest, within the
+  // limits of the policy where the given size is already taken by the rest of
+  // the block. The nonces of a sender must grow from the last one of the
+  // branch so a transaction arriving after one with a higher nonce is left
+  // out.
+  pub fn select(&self, nonces: &Nonces, reserved: usize) -> Vec<Transaction> {
+    let mut nonces = nonces.clone();
+    let mut size = reserved;
+    let mut res = Vec::new();
+    for id in &self.order {
+      let tx = &self.txs[id];
+      if nonces.get(tx.get_sender()).is_some_and(|&n| tx.get_nonce() <= n) {
+        continue;
+      }
+
+      size += self::size(tx);
+      if size > self.policy.max_bytes || res.len() >= self.policy.max_txs {
+        break;
+      }
+      nonces.insert(tx.get_sender().clone(), tx.get_nonce());
+      res.push(tx.clone());
+    }
+
+    res
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &Transaction> {
+    self.order.iter().map(move |id| &self.txs[id])
+  }
+
+  // Removes the transactions of a committed block, and the ones whose nonce
+  // can't be used anymore.
+  pub fn commit(&mut self, txs: &[Transaction]) {
+    self.last_block = Instant::now();
+
+    let mut nonces = Nonces::new();
+    for tx in txs {
+      let nonce = nonces.entry(tx.get_sender().clone()).or_default();
+      *nonce = (*nonce).max(tx.get_nonce());
+    }
+
+    let mut size = self.size;
+    self.txs.retain(|_, tx| {
+      let stale = nonces.get(tx.get_sender()).is_some_and(|&n| tx.get_nonce() <= n);
+      if stale {
+        size -= self::size(tx);
+      }
+      !stale
+    });
+    self.size = size;
+
+    let txs = &self.txs;
+    self.order.retain(|id| txs.contains_key(id));
+  }
+
+  // Adds back the transactions of a block removed from the chain.
+  pub fn revert(&mut self, txs: &[Transaction]) {
+    for tx in txs {
+      self.add(tx.clone());
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::crypto::KeyPair;
+  use crate::{Ack, Block, Event, Genesis, Message, Prepare, PrepareCertificate, Proposal, QuorumCertificate, Seed};
+  use std::time::Duration;
+
+  fn new_mempool(max_txs: usize, max_bytes: usize) -> Mempool {
+    let policy = BlockPolicy {
+      interval: Duration::from_secs(60),
+      ..BlockPolicy::default()
+    };
+    let limits = Limits {
+      mempool_txs: max_txs,
+      mempool_bytes: max_bytes,
+      ..Limits::default()
+    };
+    Mempool::new(policy, &limits)
+  }
+
+  fn nonces(mempool: &Mempool) -> Vec<u64> {
+    mempool.iter().map(Transaction::get_nonce).collect()
+  }
+
+  #[test]
+  fn bounds_the_size_of_a_proposal() {
+    let keypair = KeyPair::generate().unwrap();
+    let leader = "[::1]:3000".parse().unwrap();
+    let genesis = Genesis::new("test".to_string(), Vec::new(), Seed::default(), Vec::new());
+    let mut block = Block::genesis(&genesis).next(&keypair, leader, u64::MAX, Vec::new());
+    block.sign(&keypair);
+    let id = block.hash();
+
+    // Size added by a vote to a certificate.
+    let cert = PrepareCertificate::new(Vec::new());
+    let empty = codec::encode_unbounded(&cert).len();
+    let prepare = Prepare::new(id.clone(), u64::MAX, u64::MAX, usize::MAX, &keypair);
+    assert_eq!(codec::encode_unbounded(&PrepareCertificate::new(vec![prepare])).len() - empty, VOTE_SIZE);
+    let mut qc = QuorumCertificate::default();
+    qc.add(Ack::new(&id, u64::MAX, u64::MAX, usize::MAX, &keypair));
+    assert!(codec::encode_unbounded(&qc).len() - empty <= VOTE_SIZE);
+
+    let proposal = Message::Event(Event::Propose(Proposal::new(u64::MAX, block, Some(cert), &keypair)));
+    assert!(codec::encode_unbounded(&proposal).len() <= BLOCK_OVERHEAD);
+  }
+
+  #[test]
+  fn keeps_a_transaction_once() {
+    let mut mempool = new_mempool(10, 1 << 20);
+    let tx = Transaction::new(1, vec![1], &KeyPair::generate().unwrap());
+
+    assert!(mempool.add(tx.clone()));
+    assert!(!mempool.add(tx.clone()));
+    assert_eq!(mempool.iter().count(), 1);
+    assert_eq!(mempool.size, size(&tx));
+  }
+
+  #[test]
+  fn evicts_the_oldest_transactions() {
+    let keypair = KeyPair::generate().unwrap();
+    let tx = |nonce| Transaction::new(nonce, vec![0; 100], &keypair);
+
+    let mut mempool = new_mempool(3, 1 << 20);
+    for nonce in 1..=5 {
+      assert!(mempool.add(tx(nonce)));
+    }
+    assert_eq!(nonces(&mempool), vec![3, 4, 5]);
+
+    let mut mempool = new_mempool(10, 2 * size(&tx(1)));
+    for nonce in 1..=5 {
+      mempool.add(tx(nonce));
+    }
+    assert_eq!(nonces(&mempool), vec![4, 5]);
+    assert_eq!(mempool.size, 2 * size(&tx(1)));
+  }
+
+  #[test]
+  fn drops_the_transactions_with_used_nonces() {
+    let (alice, bob) = (KeyPair::generate().unwrap(), KeyPair::generate().unwrap());
+    let mut mempool = new_mempool(10, 1 << 20);
+    let txs = vec![
+      Transaction::new(1, vec![1], &alice),
+      Transaction::new(2, vec![2], &alice),
+      Transaction::new(3, vec![3], &alice),
+      Transaction::new(1, vec![4], &bob),
+    ];
+    for tx in &txs {
+      mempool.add(tx.clone());
+    }
+
+    // A conflicting transaction with nonce 2 is committed so the pending one
+    // can't be used anymore.
+    mempool.commit(&[txs[0].clone(), Transaction::new(2, vec![5], &alice)]);
+    assert_eq!(nonces(&mempool), vec![3, 1]);
+    assert_eq!(mempool.size, size(&txs[2]) + size(&txs[3]));
+
+    // The transactions of a block leaving the chain are pending again.
+    mempool.revert(&txs[..2]);
+    assert_eq!(nonces(&mempool), vec![3, 1, 1, 2]);
+  }
+  fn with_policy(max_txs: usize, max_bytes: usize, interval: Duration) -> Mempool {
+    let policy = BlockPolicy {
+      max_txs,
+      max_bytes,
+      interval,
+    };
+    Mempool::new(policy, &Limits::default())
+  }
+
+  #[test]
+  fn is_ready_once_a_block_is_full_or_due() {
+    let keypair = KeyPair::generate().unwrap();
+    let tx = |nonce| Transaction::new(nonce, vec![0; 100], &keypair);
+    let len = size(&tx(1));
+
+    let mut mempool = with_policy(2, 1 << 20, Duration::from_secs(60));
+    assert!(!mempool.is_ready());
+    mempool.add(tx(1));
+    assert!(!mempool.is_ready());
+    mempool.add(tx(2));
+    assert!(mempool.is_ready());
+
+    let mut mempool = with_policy(10, 2 * len, Duration::from_secs(60));
+    mempool.add(tx(1));
+    assert!(!mempool.is_ready());
+    mempool.add(tx(2));
+    assert!(mempool.is_ready());
+
+    let mut mempool = with_policy(10, 1 << 20, Duration::from_millis(10));
+    mempool.add(tx(1));
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(mempool.is_ready());
+
+    // The interval starts again with each committed block.
+    mempool.commit(&[]);
+    assert!(!mempool.is_ready());
+  }
+
+  #[test]
+  fn selects_a_block_within_the_policy() {
+    let keypair = KeyPair::generate().unwrap();
+    let tx = |nonce| Transaction::new(nonce, vec![0; 100], &keypair);
+    let len = size(&tx(1));
+
+    let mut mempool = with_policy(10, 3 * len, Duration::from_secs(60));
+    for nonce in 1..=5 {
+      mempool.add(tx(nonce));
+    }
+    let select = |nonces: &Nonces, reserved| -> Vec<u64> {
+      mempool.select(nonces, reserved).iter().map(Transaction::get_nonce).collect()
+    };
+
+    assert_eq!(select(&Nonces::new(), 0), vec![1, 2, 3]);
+    // The evidence takes the room of a transaction.
+    assert_eq!(select(&Nonces::new(), len), vec![1, 2]);
+    assert_eq!(select(&Nonces::new(), 3 * len), Vec::<u64>::new());
+    // The nonces already used on the branch are skipped.
+    let used = vec![(keypair.public_key(), 2)].into_iter().collect();
+    assert_eq!(select(&used, 0), vec![3, 4, 5]);
+
+    let mut mempool = with_policy(2, 1 << 20, Duration::from_secs(60));
+    for nonce in 1..=5 {
+      mempool.add(tx(nonce));
+    }
+    assert_eq!(mempool.select(&Nonces::new(), 0).len(), 2);
+  }
+}
@@ -1,28 +1,279 @@
+mod app;
+mod chain;
 mod context;
+mod limiter;
+mod mempool;
 mod service;
+mod store;
+mod submitters;
 
-use super::{Block};
+pub use app::Application;
+pub use store::Store;
+
+use super::codec;
+use super::crypto::KeyPair;
+use super::{Block, Genesis};
+use chain::Chain;
 use context::Context;
 use log::{info};
+use mempool::Mempool;
+use serde::Deserialize;
+use service::bft_service::BftService;
 use service::block_service::BlockService;
+use service::raft_service::RaftService;
+use store::StateFile;
 use std::io;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Consensus {
+  // The leader collects the acks of the validators in a single phase.
+  #[default]
+  Ack,
+  // Prepare and commit phases with locked blocks.
+  Bft,
+  // Leader election and log replication for nodes that trust each other. It
+  // only tolerates crashes.
+  Raft,
+}
+
+// Changes of the best branch of the chain sent to the subscribers.
+#[derive(Clone, Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum Notification {
+  // A block is appended to the tip.
+  Block(Block),
+  // The chain switched to another branch. The blocks removed from the old
+  // branch and the ones added from the new branch are ordered by height.
+  Reorg { removed: Vec<Block>, added: Vec<Block> },
+}
+
+// When the leader drains the mempool into a block. A block is proposed as
+// soon as it is full, or once the interval has passed since the last block
+// so that a few transactions don't wait forever. The interval should stay
+// well below the round timeout of the validators.
+#[derive(Clone, Copy, Debug)]
+pub struct BlockPolicy {
+  // Number of transactions of a full block.
+  pub max_txs: usize,
+  // Total size of the transactions of a full block. It cannot exceed what
+  // fits in a datagram.
+  pub max_bytes: usize,
+  pub interval: Duration,
+}
+
+impl Default for BlockPolicy {
+  fn default() -> Self {
+    BlockPolicy {
+      max_txs: 1_000,
+      max_bytes: mempool::MAX_BLOCK_SIZE,
+      interval: Duration::from_millis(100),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+  // Longest wait for a message before the timers of the node are checked.
+  pub poll: Duration,
+  // Time given to the leader of a round to get its block committed before
+  // the validators vote to skip it, with the ack and bft consensus.
+  pub round: Duration,
+  // Interval between two heartbeats of the Raft leader, which must stay
+  // below the random time a follower waits for it before it starts an
+  // election, drawn between the bounds.
+  pub heartbeat: Duration,
+  pub election: (Duration, Duration),
+}
+
+impl Default for Timeouts {
+  fn default() -> Self {
+    Timeouts {
+      poll: Duration::from_millis(100),
+      round: Duration::from_secs(1),
+      heartbeat: Duration::from_millis(100),
+      election: (Duration::from_millis(500), Duration::from_millis(1000)),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+  // Readiness events taken from each poll of the socket.
+  pub poll_events: usize,
+  // Number and total size of the pending transactions after which the
+  // oldest ones are evicted from the mempool.
+  pub mempool_txs: usize,
+  pub mempool_bytes: usize,
+}
+
+impl Default for Limits {
+  fn default() -> Self {
+    Limits {
+      poll_events: 128,
+      mempool_txs: 10_000,
+      mempool_bytes: 16 * 1024 * 1024,
+    }
+  }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+  // Voting power of the validators that must acknowledge a block before it is
+  // committed. It defaults to 2f+1 where f is the faulty power tolerated by
+  // the validators, or to a majority with Raft, which are also the smallest
+  // quorums allowed.
+  pub quorum: Option<u64>,
+  pub consensus: Consensus,
+  // Path of the block log. The chain only lives in memory when it is not
+  // set.
+  pub path: Option<PathBuf>,
+  pub policy: BlockPolicy,
+  pub timeouts: Timeouts,
+  pub limits: Limits,
+}
+
+impl Options {
+  fn get_quorum(&self, power: u64) -> u64 {
+    self.quorum.unwrap_or_else(|| self.min_quorum(power))
+  }
+
+  // Smallest power such that two quorums share an honest validator with the
+  // ack and bft consensus, which is 2f+1 where f is the faulty power
+  // tolerated, and a node with Raft.
+  fn min_quorum(&self, power: u64) -> u64 {
+    match self.consensus {
+      Consensus::Raft => power / 2 + 1,
+      _ => power - power.saturating_sub(1) / 3,
+    }
+  }
+
+  // Checks that the options can be used with the validators of the genesis.
+  pub fn validate(&self, genesis: &Genesis) -> io::Result<()> {
+    let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    let power = genesis.get_power();
+
+    let quorum = self.get_quorum(power);
+    let min = self.min_quorum(power);
+    let share = match self.consensus {
+      Consensus::Raft => "half",
+      _ => "two thirds",
+    };
+    if quorum == 0 || quorum < min || quorum > power {
+      return invalid(format!(
+        "quorum must be more than {} of the voting power, between {} and {}",
+        share, min, power
+      ));
+    }
+
+    let policy = &self.policy;
+    if policy.max_txs == 0 || policy.max_bytes == 0 || policy.max_bytes > mempool::MAX_BLOCK_SIZE {
+      return invalid(format!(
+        "blocks must allow at least a transaction and at most {} bytes",
+        mempool::MAX_BLOCK_SIZE
+      ));
+    }
+    // The blocks of the ack and bft consensus are sent with a vote of each
+    // validator.
+    let validators = genesis.get_validators().len();
+    let certificate = match self.consensus {
+      Consensus::Raft => 0,
+      _ => validators * mempool::VOTE_SIZE,
+    };
+    if policy.max_bytes + certificate + mempool::BLOCK_OVERHEAD > codec::MAX_SIZE as usize {
+      return invalid(format!(
+        "blocks of {} bytes don't fit in a datagram with the votes of {} validators",
+        policy.max_bytes, validators
+      ));
+    }
+
+    let timeouts = &self.timeouts;
+    if timeouts.poll.as_millis() == 0 || timeouts.round.as_millis() == 0 {
+      return invalid("poll and round timeouts must be at least 1ms".to_string());
+    }
+    if policy.interval >= timeouts.round {
+      return invalid("block interval must be below the round timeout".to_string());
+    }
+    let (min, max) = timeouts.election;
+    if min.as_millis() >= max.as_millis() {
+      return invalid("election timeout must have a minimum below its maximum".to_string());
+    }
+    if timeouts.heartbeat.as_millis() == 0 || timeouts.heartbeat >= min {
+      return invalid("heartbeat interval must be between 1ms and the minimum election timeout".to_string());
+    }
+
+    let limits = &self.limits;
+    if limits.poll_events == 0 {
+      return invalid("poll must take at least one event".to_string());
+    }
+    if limits.mempool_txs < policy.max_txs || limits.mempool_bytes < policy.max_bytes {
+      return invalid("mempool must hold at least a full block".to_string());
+    }
+
+    Ok(())
+  }
+}
 
 pub struct Server {
   ctx: Arc<Context>,
-  rx_wait: Receiver<Block>,
+  rx_wait: Receiver<Notification>,
   thread: Option<std::thread::JoinHandle<()>>,
   tx_close: Option<Sender<()>>,
 }
 
 impl Server {
-  pub fn new(addr: &SocketAddr, peers: Vec<SocketAddr>) -> io::Result<Self> {
+  pub fn new(
+    addr: &SocketAddr,
+    keypair: KeyPair,
+    genesis: &Genesis,
+    app: impl Application,
+    opts: Options,
+  ) -> io::Result<Self> {
+    genesis.validate()?;
+    opts.validate(genesis)?;
+    if genesis.get_validator(addr).is_none() {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} is not one of the validators", addr),
+      ));
+    }
+    let quorum = opts.get_quorum(genesis.get_power());
+
+    let chain = match &opts.path {
+      Some(path) => Chain::open(path, genesis)?,
+      None => Chain::new(genesis),
+    };
+    info!("{} runs chain {} from genesis {}", addr, genesis.get_chain_id(), genesis.hash());
+    // The consensus resumes after the last block of the chain.
+    let last = chain.last().clone();
+
     let (tx, rx_wait) = mpsc::channel();
 
-    let mut ctx = Context::new(addr.clone(), peers, tx)?;
-    ctx.register_event_handler(BlockService::new())?;
+    let mempool = Mempool::new(opts.policy, &opts.limits);
+    let peers = genesis.get_validators().to_vec();
+    let mut ctx = Context::new(*addr, keypair, peers, quorum, chain, mempool, tx)?;
+    ctx.set_poll(opts.timeouts.poll, opts.limits.poll_events);
+    ctx.register_application(app, genesis.get_state().to_vec())?;
+
+    // The votes of the node, and with Raft the blocks it hasn't committed
+    // yet, are kept next to the block log so that it never signs conflicting
+    // messages across restarts.
+    let votes = match &opts.path {
+      Some(path) => Some(StateFile::open(&path.with_extension("votes"))?),
+      None => None,
+    };
+
+    let (height, timeouts) = (last.get_height() + 1, &opts.timeouts);
+    match opts.consensus {
+      Consensus::Ack => ctx.register_event_handler(BlockService::new(height, timeouts.round, votes)?)?,
+      Consensus::Bft => ctx.register_event_handler(BftService::new(height, timeouts.round, votes)?)?,
+      Consensus::Raft => ctx.register_event_handler(RaftService::new(&last, timeouts, votes)?)?,
+    };
 
     Ok(Server {
       tx_close: None,
@@ -41,7 +292,7 @@ impl Server {
 
     self.thread = Some(std::thread::spawn(move || {
       loop {
-        if let Ok(_) = rx_close.try_recv() {
+        if rx_close.try_recv().is_ok() {
           return;
         }
 
@@ -62,7 +313,7 @@ impl Server {
     info!("{} has closed.", self.ctx.get_addr());
   }
 
-  pub fn wait(&self, f: impl Fn(Block) -> bool) {
+  pub fn wait(&self, mut f: impl FnMut(Notification) -> bool) {
     let mut is_waiting = true;
     while is_waiting {
       let msg = self.rx_wait.recv().unwrap();
@@ -0,0 +1,413 @@
+use log::{error, debug, trace};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::net::SocketAddr;
+use std::time::Duration;
+use super::sync::BlockSync;
+use super::view::{self, Rounds, View};
+use super::Service;
+use crate::{
+  Ack, Block, BlockID, Event, Prepare, PrepareCertificate, Proposal, QuorumCertificate, Transaction,
+};
+use crate::server::store::StateFile;
+use crate::server::Context;
+
+// Votes of the height being decided.
+#[derive(Default)]
+struct State {
+  // Blocks proposed for the height that this node has seen.
+  candidates: HashMap<BlockID, Block>,
+  prepares: HashMap<(u64, BlockID), Vec<Prepare>>,
+  commits: HashMap<(u64, BlockID), QuorumCertificate>,
+  // Last rounds in which this node respectively proposed, prepared and
+  // committed a block, so that it never votes twice in the same round.
+  proposed: Option<u64>,
+  prepared: Option<u64>,
+  committed: Option<u64>,
+  // Certificate of the block this node is locked on. It only prepares another
+  // block if it is justified by a certificate of a later round.
+  locked: Option<PrepareCertificate>,
+}
+
+// Votes of this node for a height, written to disk before they are sent so
+// that a restarted node doesn't vote for another block in the same round.
+#[derive(Serialize, Deserialize)]
+struct Votes {
+  height: u64,
+  proposed: Option<u64>,
+  prepared: Option<u64>,
+  committed: Option<u64>,
+  // The block is kept with the certificate so that this node can propose it
+  // again.
+  locked: Option<(PrepareCertificate, Block)>,
+}
+
+// Consensus engine with a prepare and a commit phase. A block is committed
+// once a quorum of validators sent a commit vote, which they only do after
+// seeing a quorum of prepare votes for the block in the same round.
+pub struct BftService {
+  future_queue: Mutex<Vec<Proposal>>,
+  view: Mutex<View>,
+  state: Mutex<State>,
+  votes: Option<StateFile>,
+  sync: BlockSync,
+}
+
+impl BftService {
+  // Creates the service that decides the blocks from the given height. The
+  // votes of this node for the height are restored from the file when there
+  // is one.
+  pub fn new(height: u64, round_timeout: Duration, votes: Option<StateFile>) -> io::Result<Self> {
+    let mut state = State::default();
+    let saved = match &votes {
+      Some(file) => file.load::<Votes>()?,
+      None => None,
+    };
+    if let Some(saved) = saved.filter(|v| v.height == height) {
+      state.proposed = saved.proposed;
+      state.prepared = saved.prepared;
+      state.committed = saved.committed;
+      if let Some((cert, block)) = saved.locked {
+        state.candidates.insert(block.hash(), block);
+        state.locked = Some(cert);
+      }
+    }
+
+    Ok(BftService {
+      future_queue: Mutex::new(Vec::new()),
+      view: Mutex::new(View::new(height, round_timeout)),
+      state: Mutex::new(state),
+      votes,
+      sync: BlockSync::new(Block::verify_commits),
+    })
+  }
+
+  // Writes the votes of this node for the height to disk before they are
+  // sent.
+  fn save_votes(&self, ctx: &Context, height: u64, state: &State) -> bool {
+    let file = match &self.votes {
+      Some(file) => file,
+      None => return true,
+    };
+
+    let locked = state.locked.as_ref().and_then(|cert| {
+      let block = state.candidates.get(cert.get_id()?)?;
+      Some((cert.clone(), block.clone()))
+    });
+    let votes = Votes {
+      height,
+      proposed: state.proposed,
+      prepared: state.prepared,
+      committed: state.committed,
+      locked,
+    };
+
+    match file.save(&votes) {
+      Ok(()) => true,
+      Err(e) => {
+        error!("{} can't save its votes: {}", ctx.get_addr(), e);
+        false
+      }
+    }
+  }
+
+  fn process_propose(&self, ctx: &Context, proposal: Proposal) {
+    let block = proposal.get_block();
+    let id = block.hash();
+    let round = proposal.get_round();
+
+    match ctx.get_public_key(block.get_leader()) {
+      Some(public_key) if block.verify(public_key) && block.verify_evidence(ctx.get_peers()) => (),
+      _ => {
+        error!("{} got an invalid signature for block {}", ctx.get_addr(), id);
+        return;
+      }
+    };
+
+    let justify = match proposal.get_justify() {
+      Some(cert) if cert.get_id() != Some(&id) || !cert.verify(ctx.get_peers(), ctx.get_quorum()) => {
+        error!("{} got an invalid justification for block {}", ctx.get_addr(), id);
+        return;
+      }
+      justify => justify,
+    };
+
+    {
+      let view = self.view.lock().unwrap();
+      let (height, current) = (view.get_height(), view.get_round());
+      if block.get_height() > height || round > current {
+        // Wait for the current round to finish before processing it.
+        drop(view);
+        if block.get_height() > height {
+          // This node is late and fetches the blocks in between.
+          self.sync.request(ctx, block.get_leader());
+        }
+        self.future_queue.lock().unwrap().push(proposal);
+        return;
+      }
+      if block.get_height() < height || round < current {
+        return;
+      }
+    }
+
+    {
+      let chain = ctx.lock_chain();
+      let last = chain.last();
+      let leader = view::get_leader(&chain, last, round, ctx.get_peers());
+
+      let signed = match ctx.get_public_key(leader) {
+        Some(public_key) => proposal.verify(public_key),
+        None => false,
+      };
+      if !signed {
+        error!("{} rejected proposal {} not signed by leader {}", ctx.get_addr(), id, leader);
+        return;
+      }
+
+      let public_key = ctx.get_public_key(block.get_leader()).unwrap();
+      if !last.has_next(block, public_key) {
+        error!("{} rejected proposal {} not following the tip", ctx.get_addr(), id);
+        return;
+      }
+      if !chain.verify_nonces(block) {
+        error!("{} rejected proposal {} replaying transactions", ctx.get_addr(), id);
+        return;
+      }
+      if ctx.get_state_root(&last.hash()) != Some(*block.get_state_root()) {
+        error!("{} rejected proposal {} with another state root", ctx.get_addr(), id);
+        return;
+      }
+
+      // A block from a previous round can only be proposed again with the
+      // certificate that made validators lock on it.
+      let valid = if block.get_round() == round {
+        block.has_leader(leader)
+      } else {
+        justify.is_some()
+      };
+      if !valid {
+        error!("{} rejected proposal {} for round {}", ctx.get_addr(), id, round);
+        return;
+      }
+    }
+
+    let prepare = {
+      let mut state = self.state.lock().unwrap();
+      if state.prepared >= Some(round) {
+        return;
+      }
+
+      if let Some(lock) = &state.locked {
+        let unlocked = match justify {
+          Some(cert) => cert.get_round() > lock.get_round(),
+          None => false,
+        };
+        if lock.get_id() != Some(&id) && !unlocked {
+          debug!("{} is locked and rejects block {}", ctx.get_addr(), id);
+          return;
+        }
+      }
+
+      state.prepared = Some(round);
+      state.candidates.insert(id.clone(), block.clone());
+      if !self.save_votes(ctx, block.get_height(), &state) {
+        return;
+      }
+
+      Prepare::new(id, block.get_height(), round, ctx.get_index(), ctx.get_keypair())
+    };
+
+    ctx.broadcast(&Event::Prepare(prepare.clone()));
+    self.process_prepare(ctx, prepare);
+  }
+
+  fn process_prepare(&self, ctx: &Context, prepare: Prepare) {
+    if !prepare.verify(ctx.get_peers()) {
+      error!("{} got an invalid prepare vote", ctx.get_addr());
+      return;
+    }
+
+    let (id, round) = (prepare.get_id().clone(), prepare.get_round());
+
+    let height = self.view.lock().unwrap().get_height();
+    let ack = {
+      let mut state = self.state.lock().unwrap();
+      if prepare.get_height() != height {
+        return;
+      }
+
+      let votes = state.prepares.entry((round, id.clone())).or_default();
+      if !votes.iter().any(|p| p.get_index() == prepare.get_index()) {
+        votes.push(prepare);
+      }
+      if ctx.get_power(votes.iter().map(|p| p.get_index())) < ctx.get_quorum() {
+        return;
+      }
+
+      let cert = PrepareCertificate::new(votes.clone());
+      let higher = match &state.locked {
+        Some(lock) => lock.get_round() < Some(round),
+        None => true,
+      };
+      if higher {
+        trace!("{} locks on block {} in round {}", ctx.get_addr(), id, round);
+        state.locked = Some(cert);
+      }
+
+      // The commit vote requires the block itself, which will come with the
+      // proposal if the votes arrived first.
+      if state.committed >= Some(round) || !state.candidates.contains_key(&id) {
+        return;
+      }
+      state.committed = Some(round);
+      if !self.save_votes(ctx, height, &state) {
+        return;
+      }
+
+      Ack::new(&id, height, round, ctx.get_index(), ctx.get_keypair())
+    };
+
+    ctx.broadcast(&Event::Commit(id.clone(), ack.clone()));
+    self.process_commit(ctx, id, ack);
+  }
+
+  fn process_commit(&self, ctx: &Context, id: BlockID, ack: Ack) {
+    if !ack.verify(&id, ctx.get_peers()) {
+      error!("{} got an invalid commit vote for block {}", ctx.get_addr(), id);
+      return;
+    }
+
+    let height = self.view.lock().unwrap().get_height();
+    let block = {
+      let mut state = self.state.lock().unwrap();
+      if ack.get_height() != height {
+        return;
+      }
+
+      let round = ack.get_round();
+      let qc = state.commits.entry((round, id.clone())).or_default();
+      // The votes are verified as they arrive so the signatures are not
+      // checked again.
+      qc.add(ack);
+      if ctx.get_power(qc.get_acks().iter().map(|a| a.get_index())) < ctx.get_quorum() {
+        return;
+      }
+
+      let qc = qc.clone();
+      match state.candidates.get(&id) {
+        Some(block) => {
+          let mut block = block.clone();
+          block.set_qc(qc);
+          block
+        }
+        None => return,
+      }
+    };
+
+    self.commit_block(ctx, block);
+  }
+
+}
+
+impl Rounds for BftService {
+  fn get_view(&self) -> &Mutex<View> {
+    &self.view
+  }
+
+  fn get_sync(&self) -> &BlockSync {
+    &self.sync
+  }
+
+  fn process_create_block(&self, ctx: &Context) {
+    let proposal = {
+      let chain = ctx.lock_chain();
+      let txs = {
+        let mempool = ctx.lock_mempool();
+        if mempool.is_ready() {
+          mempool.select(chain.get_nonces(), 0)
+        } else {
+          Vec::new()
+        }
+      };
+      let root = match ctx.get_state_root(&chain.last().hash()) {
+        Some(root) => root,
+        None => return,
+      };
+      let round = self.view.lock().unwrap().get_round();
+      let mut state = self.state.lock().unwrap();
+      if state.proposed >= Some(round) {
+        // A proposal is already running for this round.
+        return;
+      }
+
+      // A locked block is proposed again so that the validators locked on it
+      // can accept it.
+      let locked = state.locked.as_ref().and_then(|cert| {
+        let block = state.candidates.get(cert.get_id()?)?;
+        Some((block.clone(), cert.clone()))
+      });
+      if locked.is_none() && txs.is_empty() {
+        // Nothing to propose until the policy allows a new block.
+        return;
+      }
+      state.proposed = Some(round);
+      if !self.save_votes(ctx, chain.last().get_height() + 1, &state) {
+        return;
+      }
+
+      match locked {
+        Some((block, cert)) => Proposal::new(round, block, Some(cert), ctx.get_keypair()),
+        None => {
+          let last = chain.last();
+          let mut block = last.next(ctx.get_keypair(), *ctx.get_addr(), round, txs);
+          block.set_state_root(root);
+          block.sign(ctx.get_keypair());
+          Proposal::new(round, block, None, ctx.get_keypair())
+        }
+      }
+    };
+
+    trace!("{} asking for block {}", ctx.get_addr(), proposal.get_block().hash());
+    ctx.broadcast(&Event::Propose(proposal.clone()));
+    self.process_propose(ctx, proposal);
+  }
+
+  fn process_future(&self, ctx: &Context) {
+    let items: Vec<_> = self.future_queue.lock().unwrap().drain(..).collect();
+    for proposal in items {
+      self.process_propose(ctx, proposal);
+    }
+  }
+
+  fn reset_height(&self, _ctx: &Context, _block: &Block) {
+    *self.state.lock().unwrap() = State::default();
+  }
+}
+
+impl Service for BftService {
+  fn process_event(&self, ctx: &Context, evt: Event, from: &SocketAddr) -> io::Result<()> {
+    match evt {
+      Event::Propose(proposal) => self.process_propose(ctx, proposal),
+      Event::Prepare(prepare) => self.process_prepare(ctx, prepare),
+      Event::Commit(id, ack) => self.process_commit(ctx, id, ack),
+      Event::ViewChange(vc) => self.process_view_change(ctx, vc),
+      Event::GetBlocks(req) => self.sync.serve(ctx, req, from),
+      Event::Blocks(blocks) => self.process_blocks(ctx, blocks, from),
+      _ => debug!("{} ignores event from {}", ctx.get_addr(), from),
+    };
+
+    Ok(())
+  }
+
+  fn process_request(&self, ctx: &Context, tx: Transaction, from: &SocketAddr) -> io::Result<()> {
+    self.handle_request(ctx, tx, from);
+    Ok(())
+  }
+
+  fn process_tick(&self, ctx: &Context) -> io::Result<()> {
+    self.handle_tick(ctx);
+    Ok(())
+  }
+}
@@ -1,133 +1,443 @@
-use rand::seq::SliceRandom;
 use log::{error, info, debug, trace};
+use std::collections::HashMap;
 use std::io;
 use std::sync::Mutex;
 use std::net::SocketAddr;
+use std::time::Duration;
+use super::sync::BlockSync;
+use super::view::{self, Rounds, View};
 use super::Service;
-use crate::{Event, Block};
+use crate::crypto::Signature;
+use crate::evidence;
+use crate::{Ack, Block, BlockHeader, BlockID, Event, Evidence, Fault, Transaction};
+use crate::codec;
+use crate::server::store::StateFile;
 use crate::server::Context;
 
+// Leader, height and round of a block.
+type BlockSlot = (SocketAddr, u64, u64);
+// Validator, height and round of an ack.
+type AckSlot = (usize, u64, u64);
+
 pub struct BlockService {
-  buffer: Mutex<Vec<u8>>,
   future_queue: Mutex<Vec<Block>>,
-  blocks: Mutex<Vec<Block>>,
+  view: Mutex<View>,
+  // Block proposed by this node that is waiting for a quorum of acks.
+  proposal: Mutex<Option<Block>>,
+  // Height and round of the last block proposed by this node, so that it
+  // never runs two proposals for the same round.
+  last_proposal: Mutex<(u64, u64)>,
+  // Height and round of the last block acknowledged by this node, so that it
+  // never acknowledges two blocks for the same round.
+  last_ack: Mutex<(u64, u64)>,
+  // Blocks and acks seen for each height and round, to catch the validators
+  // signing conflicting ones.
+  seen_blocks: Mutex<HashMap<BlockSlot, (BlockHeader, Signature)>>,
+  seen_acks: Mutex<HashMap<AckSlot, (BlockID, Ack)>>,
+  // Evidence waiting to be included in a block, by offender.
+  evidence: Mutex<HashMap<usize, Evidence>>,
+  // File keeping the last proposal and ack of this node across restarts.
+  votes: Option<StateFile>,
+  sync: BlockSync,
 }
 
 impl BlockService {
-  pub fn new() -> Self {
-    BlockService{
-      buffer: Mutex::new(Vec::new()),
+  // Creates the service that decides the blocks from the given height. The
+  // votes of this node are restored from the file when there is one.
+  pub fn new(height: u64, round_timeout: Duration, votes: Option<StateFile>) -> io::Result<Self> {
+    let (last_proposal, last_ack) = match &votes {
+      Some(file) => file.load()?.unwrap_or_default(),
+      None => Default::default(),
+    };
+
+    Ok(BlockService{
       future_queue: Mutex::new(Vec::new()),
-      blocks: Mutex::new(vec![Block::new(([0, 0, 0, 0], 0).into(), vec![])]),
-    }
+      view: Mutex::new(View::new(height, round_timeout)),
+      proposal: Mutex::new(None),
+      last_proposal: Mutex::new(last_proposal),
+      last_ack: Mutex::new(last_ack),
+      seen_blocks: Mutex::new(HashMap::new()),
+      seen_acks: Mutex::new(HashMap::new()),
+      evidence: Mutex::new(HashMap::new()),
+      votes,
+      sync: BlockSync::new(Block::verify_acks),
+    })
   }
 
   fn process_propose_block(&self, ctx: &Context, block: Block) {
-    if !block.verify() {
+    if !self.verify_block(ctx, &block) {
       // block is invalid thus we abort any operation.
       return;
     }
+    self.check_block(ctx, &block);
 
-    if block.has_leader(ctx.get_addr()) {
-      if block.get_ack() == ctx.get_peers().len() {
-        ctx.propagate(&Event::ValidateBlock(block));
-      } else {
-        error!("Not enough ACKs");
+    {
+      let chain = ctx.lock_chain();
+      let last = chain.last();
+      if block.get_height() <= last.get_height() {
+        // The height has already been decided.
+        return;
+      }
+
+      let public_key = ctx.get_public_key(block.get_leader()).unwrap();
+      if !last.has_next(&block, public_key) {
+        drop(chain);
+        self.future_queue.lock().unwrap().push(block.clone());
+        // Wait for the current round to finish before processing future
+        // blocks, and fetch the blocks in between in case this node is late
+        // or on another branch.
+        self.sync.request(ctx, block.get_leader());
+        return;
+      }
+
+      let round = self.view.lock().unwrap().get_round();
+      if block.get_round() > round {
+        // Wait for the view change to reach this node.
+        self.future_queue.lock().unwrap().push(block);
+        return;
+      }
+
+      let leader = view::get_leader(&chain, last, round, ctx.get_peers());
+      if block.get_round() < round || !block.has_leader(leader) {
+        error!(
+          "{} rejected block {} from {} as {} is the leader",
+          ctx.get_addr(),
+          block.hash(),
+          block.get_leader(),
+          leader
+        );
+        return;
+      }
+
+      if !chain.verify_nonces(&block) {
+        error!("{} rejected block {} replaying transactions", ctx.get_addr(), block.hash());
+        return;
+      }
+      if ctx.get_state_root(&last.hash()) != Some(*block.get_state_root()) {
+        error!("{} rejected block {} with another state root", ctx.get_addr(), block.hash());
+        return;
       }
-      return;
     }
 
     {
-      let blocks = self.blocks.lock().unwrap();
-      let last = blocks.last().unwrap();
-      if !last.has_next(&block) {
-        let mut queue = self.future_queue.lock().unwrap();
-        queue.push(block.clone());
-        // Wait for the current round to finish before processing future blocks.
+      let mut last_ack = self.last_ack.lock().unwrap();
+      let round = (block.get_height(), block.get_round());
+      if *last_ack >= round {
+        error!("{} already acknowledged a block for round {:?}", ctx.get_addr(), round);
+        return;
+      }
+      let last_proposal = *self.last_proposal.lock().unwrap();
+      if !self.save_votes(ctx, last_proposal, round) {
         return;
       }
+      *last_ack = round;
     }
 
-    let mut block = block;
-    let index = ctx.get_peers().iter().position(|p| p == ctx.get_addr()).unwrap();
-    block.incr_ack(index);
+    // Send the acknowledgement back to the leader only.
+    let id = block.hash();
+    let ack = Ack::new(&id, block.get_height(), block.get_round(), ctx.get_index(), ctx.get_keypair());
+    ctx.send(&Event::AckBlock(id, ack), block.get_leader());
+  }
 
-    // Propagate the proposal.
-    ctx.propagate(&Event::ProposeBlock(block));
+  fn process_ack_block(&self, ctx: &Context, id: BlockID, ack: Ack) {
+    self.check_ack(ctx, &id, &ack);
+
+    let block = {
+      let mut proposal = self.proposal.lock().unwrap();
+      let block = match proposal.as_mut() {
+        Some(block) if block.hash() == id => block,
+        // Late acknowledgement of a committed block, or of a block that has
+        // been skipped.
+        _ => return,
+      };
+
+      let round = (block.get_height(), block.get_round());
+      if (ack.get_height(), ack.get_round()) != round || !ack.verify(&id, ctx.get_peers()) {
+        error!("{} got an invalid ack for block {}", ctx.get_addr(), id);
+        return;
+      }
+
+      trace!("{} got ack from {} for block {}", ctx.get_addr(), ack.get_index(), id);
+      // The acks of the proposal are verified as they arrive so the
+      // signatures are not checked again.
+      block.add_ack(ack);
+      if ctx.get_power(block.get_qc().get_acks().iter().map(|a| a.get_index())) < ctx.get_quorum() {
+        return;
+      }
+
+      proposal.take().unwrap()
+    };
+
+    ctx.broadcast(&Event::ValidateBlock(block.clone()));
+    self.commit_block(ctx, block);
   }
 
   fn process_validate_block(&self, ctx: &Context, block: Block, from: &SocketAddr) {
+    if !self.verify_block(ctx, &block) {
+      return;
+    }
+    self.check_block(ctx, &block);
+
+    if block.verify_acks(ctx.get_peers()) < ctx.get_quorum() {
+      error!("{} got validation for {} without a quorum certificate", ctx.get_addr(), block.hash());
+      return;
+    }
+
     {
-      let blocks = self.blocks.lock().unwrap();
-      for b in blocks.iter() {
-        if b.hash() == block.hash() {
+      let chain = ctx.lock_chain();
+      if chain.contains(&block.hash()) {
+        return;
+      }
+
+      // The block can follow any known block as conflicting blocks are
+      // resolved by the fork choice of the chain.
+      let parent = match chain.get_by_id(block.get_parent()) {
+        Some(parent) => parent,
+        None => {
+          debug!("{} misses the parent of block {}", ctx.get_addr(), block.hash());
           return;
         }
+      };
+
+      let public_key = ctx.get_public_key(block.get_leader()).unwrap();
+      let leader = view::get_leader(&chain, parent, block.get_round(), ctx.get_peers());
+      if !parent.has_next(&block, public_key) || !block.has_leader(leader) {
+        error!("{} got validation for {} not created by the leader", ctx.get_addr(), block.hash());
+        return;
+      }
+      if !chain.verify_nonces(&block) {
+        error!("{} got validation for {} replaying transactions", ctx.get_addr(), block.hash());
+        return;
       }
     }
 
-    let leader = self.get_leader(ctx.get_peers());
-    if block.has_leader(ctx.get_addr()) {
-      // Got our own block so everyone saw it.
-      return;
+    debug!("{} got validation for {} from {}", ctx.get_addr(), block.hash(), from);
+
+    self.commit_block(ctx, block);
+  }
+
+  // Writes the rounds of the last proposal and ack of this node to disk
+  // before they are sent, so that it doesn't sign another block for them
+  // after a restart.
+  fn save_votes(&self, ctx: &Context, last_proposal: (u64, u64), last_ack: (u64, u64)) -> bool {
+    let file = match &self.votes {
+      Some(file) => file,
+      None => return true,
+    };
+
+    match file.save(&(last_proposal, last_ack)) {
+      Ok(()) => true,
+      Err(e) => {
+        error!("{} can't save its votes: {}", ctx.get_addr(), e);
+        false
+      }
     }
+  }
 
-    debug!("{} got validation for {} from {}", ctx.get_addr(), block.hash(), from);
-    ctx.propagate(&Event::ValidateBlock(block.clone()));
+  // Checks the signature of the block against the public key registered for
+  // its leader, and the evidence it contains. Blocks from unknown leaders are
+  // rejected.
+  fn verify_block(&self, ctx: &Context, block: &Block) -> bool {
+    match ctx.get_public_key(block.get_leader()) {
+      Some(public_key) if block.verify(public_key) => (),
+      _ => {
+        error!("{} got an invalid signature for block {}", ctx.get_addr(), block.hash());
+        return false;
+      }
+    };
 
-    info!("{} is announcing block {} from leader {}", ctx.get_addr(), block.hash(), leader);
-    ctx.announce_block(&block);
+    if !block.verify_evidence(ctx.get_peers()) {
+      error!("{} got invalid evidence in block {}", ctx.get_addr(), block.hash());
+      return false;
+    }
 
-    // Store the new block.
-    {
-      let mut blocks = self.blocks.lock().unwrap();
-      blocks.push(block.clone());
+    // The acks of the certificate are checked for equivocation as well.
+    let id = block.hash();
+    for ack in block.get_qc().get_acks() {
+      self.check_ack(ctx, &id, ack);
     }
 
-    // Empty the queue of future blocks.
-    let items: Vec<_> = {
-      // Lock needs to be released for the handler.
-      let mut queue = self.future_queue.lock().unwrap();
-      queue.drain(..).collect()
+    true
+  }
+
+  // Reports the leader of a verified block if it already signed another one
+  // for the same height and round.
+  fn check_block(&self, ctx: &Context, block: &Block) {
+    let (header, signature) = match block.get_signature() {
+      Some(signature) => (block.get_header().clone(), signature.clone()),
+      None => return,
     };
-    
-    for block in items {
-      self.process_propose_block(ctx, block);
+
+    let key = (*block.get_leader(), block.get_height(), block.get_round());
+    let fault = {
+      let mut seen = self.seen_blocks.lock().unwrap();
+      match seen.get(&key) {
+        Some((other, other_sig)) if other.hash() != header.hash() => {
+          Fault::DoubleProposal(other.clone(), other_sig.clone(), header, signature)
+        }
+        Some(_) => return,
+        None => {
+          seen.insert(key, (header, signature));
+          return;
+        }
+      }
+    };
+
+    if let Some(offender) = ctx.get_peer_index(&key.0) {
+      self.report(ctx, fault, offender);
     }
+  }
+
+  // Reports the validator of a valid ack if it already acknowledged another
+  // block for the same height and round.
+  fn check_ack(&self, ctx: &Context, id: &BlockID, ack: &Ack) {
+    let key = (ack.get_index(), ack.get_height(), ack.get_round());
+    let fault = {
+      let mut seen = self.seen_acks.lock().unwrap();
+      match seen.get(&key) {
+        Some((other, _)) if other == id => return,
+        _ if !ack.verify(id, ctx.get_peers()) => return,
+        Some((other, first)) => Fault::DoubleAck(other.clone(), first.clone(), id.clone(), ack.clone()),
+        None => {
+          seen.insert(key, (id.clone(), ack.clone()));
+          return;
+        }
+      }
+    };
+
+    self.report(ctx, fault, ack.get_index());
+  }
 
-    self.future_queue.lock().unwrap().clear();
+  fn report(&self, ctx: &Context, fault: Fault, offender: usize) {
+    error!("{} caught validator {} signing conflicting messages", ctx.get_addr(), offender);
 
-    self.retry_block(ctx);
+    let evidence = Evidence::new(fault, offender, ctx.get_index(), ctx.get_keypair());
+    self.process_evidence(ctx, evidence);
   }
 
-  fn process_create_block(&self, ctx: &Context, data: &Vec<u8>) {
-    let blocks = self.blocks.lock().unwrap();
-    let mut block = blocks.last().unwrap().next(ctx.get_addr().clone(), data.clone());
-    let index = ctx.get_peers().iter().position(|p| p == ctx.get_addr()).unwrap();
-    block.incr_ack(index);
-    drop(blocks);
+  // Keeps the evidence until it is included in a block, and gossips it the
+  // first time it is seen.
+  fn process_evidence(&self, ctx: &Context, evidence: Evidence) {
+    let offender = evidence.get_offender();
 
-    trace!("{} asking for block {}", ctx.get_addr(), block.hash());
-    let evt = Event::ProposeBlock(block);
+    {
+      let chain = ctx.lock_chain();
+      let flagged = chain
+        .get_offenders(&chain.last().hash())
+        .is_some_and(|o| o.contains(&offender));
+
+      let mut pool = self.evidence.lock().unwrap();
+      if flagged || pool.contains_key(&offender) {
+        return;
+      }
+
+      if !evidence.verify(ctx.get_peers()) {
+        error!("{} got invalid evidence from {}", ctx.get_addr(), evidence.get_reporter());
+        return;
+      }
+
+      info!("{} flags validator {}", ctx.get_addr(), offender);
+      pool.insert(offender, evidence.clone());
+    }
+
+    ctx.broadcast(&Event::Evidence(evidence));
+  }
+
+}
+
+impl Rounds for BlockService {
+  fn get_view(&self) -> &Mutex<View> {
+    &self.view
+  }
 
-    ctx.propagate(&evt);
+  fn get_sync(&self) -> &BlockSync {
+    &self.sync
   }
 
-  fn get_leader<'a>(&self, peers: &'a Vec<SocketAddr>) -> &'a SocketAddr {
-    let blocks = self.blocks.lock().unwrap();
-    let block = blocks.last().unwrap();
-    let mut rng = block.get_rng();
+  fn process_create_block(&self, ctx: &Context) {
+    let mut block = {
+      let chain = ctx.lock_chain();
+      // The evidence takes its share of the size of the block.
+      let evidence: Vec<_> = self
+        .evidence
+        .lock()
+        .unwrap()
+        .values()
+        .take(evidence::MAX_PER_BLOCK)
+        .cloned()
+        .collect();
+      let txs = {
+        let mempool = ctx.lock_mempool();
+        if !mempool.is_ready() {
+          return;
+        }
+        mempool.select(chain.get_nonces(), codec::encode_unbounded(&evidence).len())
+      };
+
+      let last = chain.last();
+      let round = self.view.lock().unwrap().get_round();
+      let root = match ctx.get_state_root(&last.hash()) {
+        Some(root) => root,
+        None => return,
+      };
+
+      let mut last_proposal = self.last_proposal.lock().unwrap();
+      let proposal = (last.get_height() + 1, round);
+      if *last_proposal >= proposal {
+        // A proposal is already running for this round.
+        return;
+      }
+      if !self.save_votes(ctx, proposal, proposal) {
+        return;
+      }
+      *last_proposal = proposal;
+      *self.last_ack.lock().unwrap() = proposal;
+
+      let mut block = last.next(ctx.get_keypair(), *ctx.get_addr(), round, txs);
+      block.set_state_root(root);
+      block.set_evidence(evidence);
+      block
+    };
+
+    block.sign(ctx.get_keypair());
+    block.ack(ctx.get_index(), ctx.get_keypair());
+
+    trace!("{} asking for block {}", ctx.get_addr(), block.hash());
+    ctx.broadcast(&Event::ProposeBlock(block.clone()));
 
-    return peers.choose(&mut rng).unwrap();
+    if block.verify_acks(ctx.get_peers()) >= ctx.get_quorum() {
+      self.commit_block(ctx, block);
+    } else {
+      *self.proposal.lock().unwrap() = Some(block);
+    }
   }
 
-  fn retry_block(&self, ctx: &Context) {
-    debug!("{} is retrying block", ctx.get_addr());
+  fn process_future(&self, ctx: &Context) {
+    // Lock needs to be released for the handler.
+    let items: Vec<_> = self.future_queue.lock().unwrap().drain(..).collect();
+    for block in items {
+      self.process_propose_block(ctx, block);
+    }
+  }
 
-    let buffer = self.buffer.lock().unwrap();
-    if buffer.len() > 0 {
-      self.process_create_block(ctx, &buffer);
+  // Forgets the proposal and the messages of the previous heights, including
+  // the evidence against the validators flagged by the new block.
+  fn reset_height(&self, ctx: &Context, block: &Block) {
+    {
+      let chain = ctx.lock_chain();
+      if let Some(offenders) = chain.get_offenders(&block.hash()) {
+        self.evidence.lock().unwrap().retain(|o, _| !offenders.contains(o));
+      }
     }
+    let height = block.get_height();
+    self.seen_blocks.lock().unwrap().retain(|k, _| k.1 >= height);
+    self.seen_acks.lock().unwrap().retain(|k, _| k.1 >= height);
+    self.proposal.lock().unwrap().take();
+  }
+
+  // The proposal of the previous round, if any, is abandoned.
+  fn reset_round(&self, _ctx: &Context) {
+    self.proposal.lock().unwrap().take();
   }
 }
 
@@ -135,18 +445,25 @@ impl Service for BlockService {
   fn process_event(&self, ctx: &Context, evt: Event, from: &SocketAddr) -> io::Result<()> {
     match evt {
       Event::ProposeBlock(block) => self.process_propose_block(ctx, block),
+      Event::AckBlock(id, ack) => self.process_ack_block(ctx, id, ack),
       Event::ValidateBlock(block) => self.process_validate_block(ctx, block, from),
+      Event::ViewChange(vc) => self.process_view_change(ctx, vc),
+      Event::Evidence(evidence) => self.process_evidence(ctx, evidence),
+      Event::GetBlocks(req) => self.sync.serve(ctx, req, from),
+      Event::Blocks(blocks) => self.process_blocks(ctx, blocks, from),
+      _ => debug!("{} ignores event from {}", ctx.get_addr(), from),
     };
 
     Ok(())
   }
 
-  fn process_request(&self, ctx: &Context, data: Vec<u8>) -> io::Result<()> {
-    let mut buffer = self.buffer.lock().unwrap();
-    buffer.extend(&data);
-
-    self.process_create_block(ctx, &data);
+  fn process_request(&self, ctx: &Context, tx: Transaction, from: &SocketAddr) -> io::Result<()> {
+    self.handle_request(ctx, tx, from);
+    Ok(())
+  }
 
+  fn process_tick(&self, ctx: &Context) -> io::Result<()> {
+    self.handle_tick(ctx);
     Ok(())
   }
 }
@@ -1,12 +1,20 @@
+pub mod bft_service;
 pub mod block_service;
+pub mod raft_service;
+mod sync;
+mod view;
 
 use std::io;
 use std::net::SocketAddr;
-use crate::Event;
+use crate::{Event, Transaction};
 use crate::server::Context;
 
 pub trait Service: Send + Sync + 'static {
   fn process_event(&self, ctx: &Context, evt: Event, from: &SocketAddr) -> io::Result<()>;
 
-  fn process_request(&self, ctx: &Context, data: Vec<u8>) -> io::Result<()>;
+  fn process_request(&self, ctx: &Context, tx: Transaction, from: &SocketAddr) -> io::Result<()>;
+
+  // Called after each poll of the socket, at least every few milliseconds, so
+  // that the service can handle its timers.
+  fn process_tick(&self, ctx: &Context) -> io::Result<()>;
 }
\ No newline at end of file
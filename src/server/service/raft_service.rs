@@ -0,0 +1,637 @@
+use log::{error, info, trace};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use super::Service;
+use crate::{AppendEntries, AppendResponse, Block, Event, Message, RequestVote, Transaction, Vote};
+use crate::server::chain::Chain;
+use crate::server::store::StateFile;
+use crate::server::{Context, Timeouts};
+
+// Blocks sent in a single message to keep the datagrams small.
+const MAX_ENTRIES: u64 = 1;
+
+#[derive(PartialEq)]
+enum Role {
+  Follower,
+  Candidate,
+  Leader,
+}
+
+// Part of the state written to disk before this node answers, so that it
+// never votes twice in a term nor forgets the blocks it acknowledged after a
+// restart.
+#[derive(Serialize, Deserialize)]
+struct HardState {
+  term: u64,
+  voted_for: Option<usize>,
+  tail: Vec<Block>,
+}
+
+struct State {
+  role: Role,
+  term: u64,
+  voted_for: Option<usize>,
+  leader: Option<usize>,
+  votes: HashSet<usize>,
+  // Blocks of the log following the tip of the chain. They are not committed
+  // yet and can be replaced by a new leader.
+  tail: Vec<Block>,
+  commit: u64,
+  // Height of the next block to send to each node and of the last one known
+  // to be replicated on it, when this node is the leader.
+  next: Vec<u64>,
+  matched: Vec<u64>,
+  deadline: Instant,
+  // Bounds of the random time a follower waits for the leader before it
+  // starts an election.
+  election: (Duration, Duration),
+  // Requests received while no leader is known.
+  pending: Vec<Transaction>,
+  // The term, the vote or the tail changed since they were written to disk.
+  dirty: bool,
+}
+
+impl State {
+  fn new(term: u64, commit: u64, election: (Duration, Duration)) -> Self {
+    State {
+      role: Role::Follower,
+      term,
+      voted_for: None,
+      leader: None,
+      votes: HashSet::new(),
+      tail: Vec::new(),
+      commit,
+      next: Vec::new(),
+      matched: Vec::new(),
+      deadline: election_deadline(election),
+      election,
+      pending: Vec::new(),
+      dirty: false,
+    }
+  }
+
+  fn get_block<'a>(&'a self, chain: &'a Chain, height: u64) -> Option<&'a Block> {
+    let tip = chain.last().get_height();
+    if height <= tip {
+      chain.get(height)
+    } else {
+      self.tail.get((height - tip - 1) as usize)
+    }
+  }
+
+  fn last<'a>(&'a self, chain: &'a Chain) -> &'a Block {
+    self.tail.last().unwrap_or_else(|| chain.last())
+  }
+
+  // Follows the leader of the given term, which is at least the current one.
+  fn step_down(&mut self, term: u64) {
+    if term > self.term {
+      self.term = term;
+      self.voted_for = None;
+      self.leader = None;
+      self.dirty = true;
+    }
+    if self.role == Role::Leader {
+      self.deadline = election_deadline(self.election);
+    }
+    self.role = Role::Follower;
+  }
+
+  fn reject(&self, chain: &Chain) -> AppendResponse {
+    AppendResponse::new(self.term, false, self.last(chain).get_height())
+  }
+}
+
+fn election_deadline((min, max): (Duration, Duration)) -> Instant {
+  Instant::now() + Duration::from_millis(rand::thread_rng().gen_range(min.as_millis() as u64, max.as_millis() as u64))
+}
+
+// Consensus engine for nodes that trust each other. It only tolerates crashes
+// but needs a single round trip from the leader to a majority to commit. The
+// log is made of blocks so that the committed ones are appended to the chain
+// exactly like with the other engines.
+pub struct RaftService {
+  state: Mutex<State>,
+  // Interval between two heartbeats of the leader.
+  heartbeat: Duration,
+  file: Option<StateFile>,
+}
+
+impl RaftService {
+  // Creates the service that resumes after the given block. Its round is
+  // the term of the log so that the next terms are higher. The term, the
+  // vote and the blocks not committed yet are restored from the file when
+  // there is one.
+  pub fn new(last: &Block, timeouts: &Timeouts, file: Option<StateFile>) -> io::Result<Self> {
+    let mut state = State::new(last.get_round(), last.get_height(), timeouts.election);
+
+    let saved = match &file {
+      Some(file) => file.load::<HardState>()?,
+      None => None,
+    };
+    if let Some(saved) = saved {
+      if saved.term >= state.term {
+        state.term = saved.term;
+        state.voted_for = saved.voted_for;
+      }
+      // The blocks committed before the restart are already in the chain.
+      state.tail = saved.tail.into_iter().filter(|b| b.get_height() > last.get_height()).collect();
+      if state.tail.first().is_some_and(|b| b.get_parent() != &last.hash()) {
+        state.tail.clear();
+      }
+    }
+
+    Ok(RaftService {
+      state: Mutex::new(state),
+      heartbeat: timeouts.heartbeat,
+      file,
+    })
+  }
+
+  // Writes the term, the vote and the tail to disk if they changed, and
+  // returns false if this node must not answer as they couldn't be.
+  fn save(&self, ctx: &Context, state: &mut State) -> bool {
+    let file = match &self.file {
+      Some(file) if state.dirty => file,
+      _ => return true,
+    };
+
+    let hard = HardState {
+      term: state.term,
+      voted_for: state.voted_for,
+      tail: state.tail.clone(),
+    };
+    match file.save(&hard) {
+      Ok(()) => {
+        state.dirty = false;
+        true
+      }
+      Err(e) => {
+        error!("{} can't save its state: {}", ctx.get_addr(), e);
+        false
+      }
+    }
+  }
+
+  fn process_request_vote(&self, ctx: &Context, rv: RequestVote, from: &SocketAddr) {
+    let index = match ctx.get_peer_index(from) {
+      Some(index) => index,
+      None => return,
+    };
+
+    let vote = {
+      let chain = ctx.lock_chain();
+      let mut state = self.state.lock().unwrap();
+      if rv.get_term() > state.term {
+        state.step_down(rv.get_term());
+      }
+
+      // The vote is only given to a candidate with a log at least as recent
+      // as this one so that the committed blocks are never lost.
+      let last = state.last(&chain);
+      let up_to_date = (rv.get_last_term(), rv.get_last_height()) >= (last.get_round(), last.get_height());
+      let granted = rv.get_term() == state.term
+        && state.voted_for.is_none_or(|i| i == index)
+        && up_to_date;
+      if granted {
+        state.voted_for = Some(index);
+        state.dirty = true;
+        state.deadline = election_deadline(state.election);
+      }
+      if !self.save(ctx, &mut state) {
+        return;
+      }
+
+      Vote::new(state.term, granted)
+    };
+
+    ctx.send(&Event::Vote(vote), from);
+  }
+
+  fn process_vote(&self, ctx: &Context, vote: Vote, from: &SocketAddr) {
+    let index = match ctx.get_peer_index(from) {
+      Some(index) => index,
+      None => return,
+    };
+
+    {
+      let chain = ctx.lock_chain();
+      let mut state = self.state.lock().unwrap();
+      if vote.get_term() > state.term {
+        state.step_down(vote.get_term());
+        return;
+      }
+      if state.role != Role::Candidate || vote.get_term() != state.term || !vote.is_granted() {
+        return;
+      }
+
+      state.votes.insert(index);
+      if ctx.get_power(state.votes.iter().copied()) >= ctx.get_quorum() {
+        self.become_leader(ctx, &chain, &mut state);
+      }
+    }
+
+    self.apply(ctx);
+  }
+
+  fn process_append_entries(&self, ctx: &Context, ae: AppendEntries, from: &SocketAddr) {
+    let index = match ctx.get_peer_index(from) {
+      Some(index) => index,
+      None => return,
+    };
+
+    let res = {
+      let chain = ctx.lock_chain();
+      let mut state = self.state.lock().unwrap();
+      let res = if ae.get_term() < state.term {
+        state.reject(&chain)
+      } else {
+        state.step_down(ae.get_term());
+        state.leader = Some(index);
+        state.deadline = election_deadline(state.election);
+
+        for tx in state.pending.drain(..) {
+          ctx.send_message(&Message::Request(tx), from);
+        }
+
+        self.append_entries(ctx, &chain, &mut state, &ae)
+      };
+
+      // The blocks are on disk before the leader counts them as replicated.
+      if !self.save(ctx, &mut state) {
+        return;
+      }
+      res
+    };
+
+    ctx.send(&Event::AppendResponse(res), from);
+    self.apply(ctx);
+  }
+
+  // Appends the blocks of the leader after removing the ones of this node
+  // that conflict with them.
+  fn append_entries(&self, ctx: &Context, chain: &Chain, state: &mut State, ae: &AppendEntries) -> AppendResponse {
+    match state.get_block(chain, ae.get_prev_height()) {
+      Some(prev) if prev.get_round() == ae.get_prev_term() => (),
+      _ => return state.reject(chain),
+    };
+
+    let tip = chain.last().get_height();
+    for block in ae.get_blocks() {
+      let height = block.get_height();
+      match state.get_block(chain, height).map(|b| b.get_round()) {
+        // Blocks with the same height and term are the same.
+        Some(term) if term == block.get_round() => continue,
+        Some(_) if height <= tip => {
+          error!("{} got a block conflicting with the chain at height {}", ctx.get_addr(), height);
+          return state.reject(chain);
+        }
+        Some(_) => {
+          state.tail.truncate((height - tip - 1) as usize);
+          state.dirty = true;
+        }
+        None => (),
+      };
+
+      let valid = match ctx.get_public_key(block.get_leader()) {
+        Some(public_key) => block.verify(public_key) && state.last(chain).has_next(block, public_key),
+        None => false,
+      };
+      if !valid {
+        error!("{} got an invalid block at height {}", ctx.get_addr(), height);
+        return state.reject(chain);
+      }
+
+      state.tail.push(block.clone());
+      state.dirty = true;
+    }
+
+    let height = ae.get_prev_height() + ae.get_blocks().len() as u64;
+    state.commit = state.commit.max(ae.get_commit().min(height));
+
+    AppendResponse::new(state.term, true, height)
+  }
+
+  fn process_append_response(&self, ctx: &Context, res: AppendResponse, from: &SocketAddr) {
+    let index = match ctx.get_peer_index(from) {
+      Some(index) => index,
+      None => return,
+    };
+
+    {
+      let chain = ctx.lock_chain();
+      let mut state = self.state.lock().unwrap();
+      if res.get_term() > state.term {
+        state.step_down(res.get_term());
+        return;
+      }
+      if state.role != Role::Leader || res.get_term() != state.term {
+        return;
+      }
+
+      if res.is_success() {
+        state.matched[index] = state.matched[index].max(res.get_height());
+        state.next[index] = state.matched[index] + 1;
+        self.advance_commit(ctx, &chain, &mut state);
+
+        // Keep sending the missing blocks without waiting for the heartbeat.
+        if state.next[index] <= state.last(&chain).get_height() {
+          self.send_append(ctx, &chain, &state, index);
+        }
+      } else {
+        // Go back until the logs match, skipping the blocks the follower
+        // doesn't have.
+        let next = (state.next[index] - 1).min(res.get_height() + 1);
+        state.next[index] = next.max(1);
+        self.send_append(ctx, &chain, &state, index);
+      }
+    }
+
+    self.apply(ctx);
+  }
+
+  // The leader is silent so this node tries to replace it.
+  fn start_election(&self, ctx: &Context, chain: &Chain, state: &mut State) {
+    state.term += 1;
+    state.role = Role::Candidate;
+    state.voted_for = Some(ctx.get_index());
+    state.leader = None;
+    state.votes = vec![ctx.get_index()].into_iter().collect();
+    state.deadline = election_deadline(state.election);
+    state.dirty = true;
+    info!("{} starts an election for term {}", ctx.get_addr(), state.term);
+    if !self.save(ctx, state) {
+      return;
+    }
+
+    if ctx.get_power(state.votes.iter().copied()) >= ctx.get_quorum() {
+      self.become_leader(ctx, chain, state);
+    } else {
+      let last = state.last(chain);
+      ctx.broadcast(&Event::RequestVote(RequestVote::new(state.term, last.get_height(), last.get_round())));
+    }
+  }
+
+  fn become_leader(&self, ctx: &Context, chain: &Chain, state: &mut State) {
+    info!("{} is elected leader for term {}", ctx.get_addr(), state.term);
+
+    let n = ctx.get_peers().len();
+    let height = state.last(chain).get_height();
+    state.role = Role::Leader;
+    state.leader = Some(ctx.get_index());
+    state.next = vec![height + 1; n];
+    state.matched = vec![0; n];
+    state.matched[ctx.get_index()] = height;
+
+    // The requests waiting for a leader are already in the mempool.
+    state.pending.clear();
+    self.build(ctx, chain, state);
+    self.broadcast_append(ctx, chain, state);
+  }
+
+  // Appends a block with the pending transactions at the end of the log of
+  // the leader when the policy allows it, and returns true in that case. A
+  // single block of the current term is replicated at a time, and the nonces
+  // of the blocks not applied yet are taken into account so that their
+  // transactions are not included twice. The blocks of the previous terms
+  // are only committed with a block of the current term, so one is appended
+  // even without transactions when the log holds such blocks.
+  fn build(&self, ctx: &Context, chain: &Chain, state: &mut State) -> bool {
+    if state.tail.iter().any(|b| b.get_round() == state.term) {
+      return false;
+    }
+    let forced = !state.tail.is_empty();
+
+    let txs: Vec<_> = {
+      let mempool = ctx.lock_mempool();
+      if !mempool.is_ready() && !forced {
+        return false;
+      }
+
+      let mut nonces = chain.get_nonces().clone();
+      for tx in state.tail.iter().flat_map(|b| b.get_txs()) {
+        nonces.insert(tx.get_sender().clone(), tx.get_nonce());
+      }
+      mempool.select(&nonces, 0)
+    };
+    if txs.is_empty() && !forced {
+      return false;
+    }
+
+    // The blocks of the log are not executed until they are committed.
+    let root = if state.tail.is_empty() {
+      match ctx.get_state_root(&chain.last().hash()) {
+        Some(root) => root,
+        None => return false,
+      }
+    } else {
+      ctx.speculate_state_root(chain, &state.tail)
+    };
+
+    let mut block = state.last(chain).next(ctx.get_keypair(), *ctx.get_addr(), state.term, txs);
+    block.set_state_root(root);
+    block.sign(ctx.get_keypair());
+    trace!("{} appends block {} at height {}", ctx.get_addr(), block.hash(), block.get_height());
+
+    // The leader counts itself as a replica once the block is on disk.
+    let height = block.get_height();
+    state.tail.push(block);
+    state.dirty = true;
+    if !self.save(ctx, state) {
+      state.tail.pop();
+      return false;
+    }
+    state.matched[ctx.get_index()] = height;
+    self.advance_commit(ctx, chain, state);
+    true
+  }
+
+  // Sends the missing blocks to every follower, or a heartbeat when they are
+  // up to date.
+  fn broadcast_append(&self, ctx: &Context, chain: &Chain, state: &mut State) {
+    for index in 0..ctx.get_peers().len() {
+      if index != ctx.get_index() {
+        self.send_append(ctx, chain, state, index);
+      }
+    }
+
+    state.deadline = Instant::now() + self.heartbeat;
+  }
+
+  fn send_append(&self, ctx: &Context, chain: &Chain, state: &State, index: usize) {
+    let next = state.next[index];
+    let prev = match state.get_block(chain, next - 1) {
+      Some(prev) => prev,
+      None => return,
+    };
+
+    let blocks = (next..next + MAX_ENTRIES)
+      .filter_map(|h| state.get_block(chain, h))
+      .cloned()
+      .collect();
+
+    let ae = AppendEntries::new(state.term, prev.get_height(), prev.get_round(), blocks, state.commit);
+    ctx.send(&Event::AppendEntries(ae), ctx.get_peers()[index].get_addr());
+  }
+
+  // Commits the last block of the current term replicated on a quorum, and
+  // thus every block before it.
+  fn advance_commit(&self, ctx: &Context, chain: &Chain, state: &mut State) {
+    let last = state.last(chain).get_height();
+    for height in (state.commit + 1..=last).rev() {
+      let term = state.get_block(chain, height).map(|b| b.get_round());
+      let replicas = ctx.get_power((0..state.matched.len()).filter(|&i| state.matched[i] >= height));
+
+      if term == Some(state.term) && replicas >= ctx.get_quorum() {
+        state.commit = height;
+        return;
+      }
+    }
+  }
+
+  // Moves the committed blocks of the log to the chain.
+  fn apply(&self, ctx: &Context) {
+    loop {
+      let block = {
+        let chain = ctx.lock_chain();
+        let mut state = self.state.lock().unwrap();
+        if chain.last().get_height() >= state.commit || state.tail.is_empty() {
+          return;
+        }
+
+        state.tail.remove(0)
+      };
+
+      info!(
+        "{} is announcing block {} at height {} from leader {}",
+        ctx.get_addr(),
+        block.hash(),
+        block.get_height(),
+        block.get_leader()
+      );
+      ctx.announce_block(&block);
+    }
+  }
+}
+
+impl Service for RaftService {
+  fn process_event(&self, ctx: &Context, evt: Event, from: &SocketAddr) -> io::Result<()> {
+    match evt {
+      Event::RequestVote(rv) => self.process_request_vote(ctx, rv, from),
+      Event::Vote(vote) => self.process_vote(ctx, vote, from),
+      Event::AppendEntries(ae) => self.process_append_entries(ctx, ae, from),
+      Event::AppendResponse(res) => self.process_append_response(ctx, res, from),
+      _ => trace!("{} ignores event from {}", ctx.get_addr(), from),
+    };
+
+    Ok(())
+  }
+
+  fn process_request(&self, ctx: &Context, tx: Transaction, from: &SocketAddr) -> io::Result<()> {
+    if !ctx.add_transaction(tx.clone(), from) {
+      trace!("{} ignores an invalid or known transaction from {}", ctx.get_addr(), from);
+      return Ok(());
+    }
+
+    {
+      let chain = ctx.lock_chain();
+      let mut state = self.state.lock().unwrap();
+
+      if state.role == Role::Leader {
+        if self.build(ctx, &chain, &mut state) {
+          self.broadcast_append(ctx, &chain, &mut state);
+        }
+      } else {
+        match state.leader.map(|i| ctx.get_peers()[i].get_addr()) {
+          Some(leader) if leader != from => {
+            trace!("{} forwards request to leader {}", ctx.get_addr(), leader);
+            ctx.send_message(&Message::Request(tx), leader);
+          }
+          // The request is forwarded once the leader is known.
+          _ => state.pending.push(tx),
+        };
+      }
+    }
+
+    self.apply(ctx);
+
+    Ok(())
+  }
+
+  fn process_tick(&self, ctx: &Context) -> io::Result<()> {
+    {
+      let chain = ctx.lock_chain();
+      let mut state = self.state.lock().unwrap();
+      if state.role == Role::Leader {
+        // A new block is sent right away, otherwise a heartbeat when it is
+        // due.
+        if self.build(ctx, &chain, &mut state) || Instant::now() >= state.deadline {
+          self.broadcast_append(ctx, &chain, &mut state);
+        }
+      } else if Instant::now() >= state.deadline {
+        self.start_election(ctx, &chain, &mut state);
+      }
+    }
+
+    self.apply(ctx);
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::crypto::KeyPair;
+  use crate::server::mempool::Mempool;
+  use crate::server::{BlockPolicy, Limits};
+  use crate::{Genesis, Peer, Seed};
+  use std::sync::mpsc;
+
+  #[test]
+  fn commits_the_blocks_of_a_previous_term() {
+    let pkcs8: Vec<_> = (0..3).map(|_| KeyPair::generate_pkcs8().unwrap()).collect();
+    let keys: Vec<_> = pkcs8.iter().map(|k| KeyPair::from_pkcs8(k).unwrap()).collect();
+    let addrs: Vec<_> = (0..3)
+      .map(|_| std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap())
+      .collect();
+    let peers: Vec<_> = (0..3).map(|i| Peer::new(addrs[i], keys[i].public_key(), 1)).collect();
+    let genesis = Genesis::new("test".to_string(), peers.clone(), Seed::default(), Vec::new());
+
+    let chain = Chain::new(&genesis);
+    let mempool = Mempool::new(BlockPolicy::default(), &Limits::default());
+    let (tx, _rx) = mpsc::channel();
+    let ctx = Context::new(addrs[0], KeyPair::from_pkcs8(&pkcs8[0]).unwrap(), peers, 2, chain, mempool, tx).unwrap();
+
+    let timeouts = Timeouts {
+      election: (Duration::from_millis(1), Duration::from_millis(2)),
+      ..Timeouts::default()
+    };
+    let service = RaftService::new(ctx.lock_chain().last(), &timeouts, None).unwrap();
+
+    // The leader of the first term crashes after replicating its block on
+    // this node only.
+    let mut block = ctx.lock_chain().last().next(&keys[1], addrs[1], 1, Vec::new());
+    block.sign(&keys[1]);
+    let ae = AppendEntries::new(1, 0, 0, vec![block], 0);
+    service.process_event(&ctx, Event::AppendEntries(ae), &addrs[1]).unwrap();
+
+    std::thread::sleep(Duration::from_millis(5));
+    service.process_tick(&ctx).unwrap();
+    service.process_event(&ctx, Event::Vote(Vote::new(2, true)), &addrs[2]).unwrap();
+    {
+      let state = service.state.lock().unwrap();
+      assert!(state.role == Role::Leader);
+      assert_eq!(state.tail.iter().map(|b| b.get_round()).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    let res = AppendResponse::new(2, true, 2);
+    service.process_event(&ctx, Event::AppendResponse(res), &addrs[2]).unwrap();
+    assert_eq!(ctx.lock_chain().last().get_height(), 2);
+  }
+}
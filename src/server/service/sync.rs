@@ -0,0 +1,135 @@
+use log::{error, info, trace};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use super::view;
+use crate::{Block, BlockRequest, Event, Peer};
+use crate::server::chain;
+use crate::server::Context;
+
+// Minimum time between two requests for missing blocks, which are triggered
+// by every message of a later height.
+const SYNC_INTERVAL: Duration = Duration::from_millis(500);
+
+// Fetches the blocks missed by a node from the other validators, and serves
+// its own blocks to them.
+pub struct BlockSync {
+  last_request: Mutex<Option<Instant>>,
+  // Returns the voting power of the certificate of a block, which depends on
+  // the consensus.
+  certify: fn(&Block, &[Peer]) -> u64,
+}
+
+impl BlockSync {
+  pub fn new(certify: fn(&Block, &[Peer]) -> u64) -> Self {
+    BlockSync {
+      last_request: Mutex::new(None),
+      certify,
+    }
+  }
+
+  // Asks the peer for the blocks following the tip of the chain, unless a
+  // request has been sent recently.
+  pub fn request(&self, ctx: &Context, to: &SocketAddr) {
+    {
+      let mut last_request = self.last_request.lock().unwrap();
+      if last_request.is_some_and(|t| t.elapsed() < SYNC_INTERVAL) {
+        return;
+      }
+      *last_request = Some(Instant::now());
+    }
+
+    let height = ctx.lock_chain().last().get_height() + 1;
+    trace!("{} asks {} for blocks from height {}", ctx.get_addr(), to, height);
+    ctx.send(&Event::GetBlocks(BlockRequest::Height(height)), to);
+  }
+
+  pub fn serve(&self, ctx: &Context, req: BlockRequest, from: &SocketAddr) {
+    let blocks = {
+      let chain = ctx.lock_chain();
+      match req {
+        BlockRequest::Height(height) => {
+          let blocks = (height..).map_while(|h| chain.get(h));
+          chain::take_answer(blocks)
+        }
+        BlockRequest::Ancestors(id) => {
+          // The genesis block is left out as every node has it.
+          let blocks = std::iter::successors(chain.get_by_id(&id), |b| chain.get_by_id(b.get_parent()))
+            .take_while(|b| b.get_height() > 0);
+          let mut blocks = chain::take_answer(blocks);
+          blocks.reverse();
+          blocks
+        }
+      }
+    };
+
+    if !blocks.is_empty() {
+      ctx.send(&Event::Blocks(blocks), from);
+    }
+  }
+
+  // Verifies the blocks against the chain and inserts them. It returns the
+  // last block that became the tip of the chain, if any, and asks for the
+  // next blocks in that case.
+  pub fn receive(&self, ctx: &Context, blocks: Vec<Block>, from: &SocketAddr) -> Option<Block> {
+    let mut tip = None;
+
+    for block in blocks {
+      let id = block.hash();
+
+      let valid = {
+        let chain = ctx.lock_chain();
+        if chain.contains(&id) {
+          continue;
+        }
+
+        let parent = match chain.get_by_id(block.get_parent()) {
+          Some(parent) => parent,
+          None => {
+            // The peer is on another branch so the node goes back in its
+            // history until the branches meet.
+            ctx.send(&Event::GetBlocks(BlockRequest::Ancestors(block.get_parent().clone())), from);
+            break;
+          }
+        };
+
+        match ctx.get_public_key(block.get_leader()) {
+          Some(public_key) => {
+            let leader = view::get_leader(&chain, parent, block.get_round(), ctx.get_peers());
+            block.verify(public_key)
+              && block.verify_evidence(ctx.get_peers())
+              && parent.has_next(&block, public_key)
+              && chain.verify_nonces(&block)
+              && block.has_leader(leader)
+              && (self.certify)(&block, ctx.get_peers()) >= ctx.get_quorum()
+          }
+          None => false,
+        }
+      };
+
+      if !valid {
+        error!("{} got an invalid block {} from {}", ctx.get_addr(), id, from);
+        break;
+      }
+
+      info!(
+        "{} is syncing block {} at height {} from {}",
+        ctx.get_addr(),
+        id,
+        block.get_height(),
+        from
+      );
+      if ctx.announce_block(&block) {
+        tip = Some(block);
+      }
+    }
+
+    if tip.is_some() {
+      // The peer might have more blocks than what fits in an answer.
+      *self.last_request.lock().unwrap() = None;
+      self.request(ctx, from);
+    }
+
+    tip
+  }
+}
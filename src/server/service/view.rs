@@ -0,0 +1,272 @@
+use log::{debug, error, info, trace};
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use super::sync::BlockSync;
+use crate::{Block, Event, Message, Peer, Transaction, ViewChange};
+use crate::server::chain::Chain;
+use crate::server::Context;
+
+// Returns the leader scheduled for the given round of the block following the
+// given one. The seed of the block gives the order of the candidates, which
+// leaves out the validators flagged on its branch unless all of them are.
+pub fn get_leader<'a>(chain: &Chain, last: &Block, round: u64, peers: &'a [Peer]) -> &'a SocketAddr {
+  let offenders = chain.get_offenders(&last.hash());
+  let mut candidates: Vec<&Peer> = peers
+    .iter()
+    .enumerate()
+    .filter(|(i, _)| offenders.is_none_or(|o| !o.contains(i)))
+    .map(|(_, p)| p)
+    .collect();
+  if candidates.is_empty() {
+    candidates = peers.iter().collect();
+  }
+
+  let mut rng = last.get_rng();
+  candidates.shuffle(&mut rng);
+
+  candidates[round as usize % candidates.len()].get_addr()
+}
+
+// State of the height being decided.
+#[derive(Default)]
+pub struct View {
+  height: u64,
+  round: u64,
+  // Time given to the leader of a round to get its block committed before
+  // the validators vote to skip it.
+  timeout: Duration,
+  // The timer only runs while this node knows about pending requests.
+  deadline: Option<Instant>,
+  votes: HashMap<u64, HashSet<usize>>,
+}
+
+impl View {
+  pub fn new(height: u64, timeout: Duration) -> Self {
+    View {
+      height,
+      timeout,
+      ..Default::default()
+    }
+  }
+
+  // Returns the view of the first round of the given height.
+  pub fn next(&self, height: u64) -> Self {
+    View::new(height, self.timeout)
+  }
+
+  pub fn get_height(&self) -> u64 {
+    self.height
+  }
+
+  pub fn get_round(&self) -> u64 {
+    self.round
+  }
+
+  // Starts the timer unless it is already running.
+  pub fn arm(&mut self) {
+    if self.deadline.is_none() {
+      self.deadline = Some(Instant::now() + self.timeout);
+    }
+  }
+
+  // Returns the vote of this node for the next round when the timer has
+  // expired. The timer restarts so that the vote is sent again until a
+  // quorum is reached.
+  pub fn expire(&mut self, ctx: &Context) -> Option<ViewChange> {
+    match self.deadline {
+      Some(deadline) if Instant::now() >= deadline => (),
+      _ => return None,
+    };
+
+    self.deadline = Some(Instant::now() + self.timeout);
+    Some(ViewChange::new(self.height, self.round + 1, ctx.get_index(), ctx.get_keypair()))
+  }
+
+  // Records a verified vote and returns true when a quorum agreed to move to
+  // its round.
+  pub fn add_vote(&mut self, vc: &ViewChange, ctx: &Context) -> bool {
+    if vc.get_height() != self.height || vc.get_round() <= self.round {
+      return false;
+    }
+
+    // Another validator is waiting for a block so this one joins.
+    self.arm();
+
+    let votes = self.votes.entry(vc.get_round()).or_default();
+    votes.insert(vc.get_index());
+    if ctx.get_power(votes.iter().copied()) < ctx.get_quorum() {
+      return false;
+    }
+
+    self.round = vc.get_round();
+    self.deadline = Some(Instant::now() + self.timeout);
+    true
+  }
+}
+
+// Consensus deciding the blocks height after height, in rounds led by the
+// scheduled leader which validators skip by voting for a view change. The
+// services only define how a block is proposed and what they keep for a
+// height, and share the handling of the heights and rounds.
+pub trait Rounds {
+  fn get_view(&self) -> &Mutex<View>;
+
+  fn get_sync(&self) -> &BlockSync;
+
+  // Proposes a block with the pending transactions when the policy allows it.
+  fn process_create_block(&self, ctx: &Context);
+
+  // Processes again the messages kept for a later height or round.
+  fn process_future(&self, ctx: &Context);
+
+  // Forgets what belongs to the heights up to the block.
+  fn reset_height(&self, ctx: &Context, block: &Block);
+
+  // Forgets what belongs to the previous round.
+  fn reset_round(&self, _ctx: &Context) {}
+
+  // Announces a decided block, then moves on to the next height when it is
+  // the new tip of the chain.
+  fn commit_block(&self, ctx: &Context, block: Block) {
+    info!(
+      "{} is announcing block {} at height {} from leader {}",
+      ctx.get_addr(),
+      block.hash(),
+      block.get_height(),
+      block.get_leader()
+    );
+    if ctx.announce_block(&block) {
+      self.start_height(ctx, &block);
+    }
+  }
+
+  // Moves on to the height following the block which is the new tip of the
+  // chain.
+  fn start_height(&self, ctx: &Context, block: &Block) {
+    self.reset_height(ctx, block);
+
+    // Start the first round of the next height.
+    {
+      let pending = !ctx.lock_mempool().is_empty();
+      let mut view = self.get_view().lock().unwrap();
+      *view = view.next(block.get_height() + 1);
+      if pending {
+        view.arm();
+      }
+    }
+
+    self.process_future(ctx);
+    self.retry_block(ctx);
+  }
+
+  fn process_blocks(&self, ctx: &Context, blocks: Vec<Block>, from: &SocketAddr) {
+    if let Some(block) = self.get_sync().receive(ctx, blocks, from) {
+      self.start_height(ctx, &block);
+    }
+  }
+
+  fn process_view_change(&self, ctx: &Context, vc: ViewChange) {
+    if !vc.verify(ctx.get_peers()) {
+      error!("{} got an invalid view change vote", ctx.get_addr());
+      return;
+    }
+
+    let height = {
+      let mut view = self.get_view().lock().unwrap();
+      if view.add_vote(&vc, ctx) {
+        drop(view);
+        self.start_round(ctx);
+        return;
+      }
+      view.get_height()
+    };
+
+    // The other validators are deciding a later height.
+    if vc.get_height() > height {
+      if let Some(peer) = ctx.get_peers().get(vc.get_index()) {
+        self.get_sync().request(ctx, peer.get_addr());
+      }
+    }
+  }
+
+  // Moves to the round that has been agreed on by the validators.
+  fn start_round(&self, ctx: &Context) {
+    let (round, leader) = self.get_current_leader(ctx);
+    info!("{} moves to round {} with leader {}", ctx.get_addr(), round, leader);
+
+    self.reset_round(ctx);
+    self.process_future(ctx);
+
+    if leader == *ctx.get_addr() {
+      self.retry_block(ctx);
+    } else {
+      // Make sure the new leader knows about the pending requests.
+      for tx in ctx.lock_mempool().iter() {
+        ctx.send_message(&Message::Request(tx.clone()), &leader);
+      }
+    }
+  }
+
+  fn get_current_leader(&self, ctx: &Context) -> (u64, SocketAddr) {
+    let chain = ctx.lock_chain();
+    let round = self.get_view().lock().unwrap().get_round();
+
+    (round, *get_leader(&chain, chain.last(), round, ctx.get_peers()))
+  }
+
+  fn retry_block(&self, ctx: &Context) {
+    if self.get_current_leader(ctx).1 != *ctx.get_addr() {
+      return;
+    }
+
+    debug!("{} is retrying block", ctx.get_addr());
+    self.process_create_block(ctx);
+  }
+
+  fn handle_request(&self, ctx: &Context, tx: Transaction, from: &SocketAddr) {
+    let (_, leader) = self.get_current_leader(ctx);
+
+    // The request is kept so that it can be proposed by the next leader if
+    // the current one doesn't answer in time.
+    if !ctx.add_transaction(tx.clone(), from) {
+      trace!("{} ignores an invalid or known transaction from {}", ctx.get_addr(), from);
+      return;
+    }
+    self.get_view().lock().unwrap().arm();
+
+    if leader == *ctx.get_addr() {
+      self.process_create_block(ctx);
+    } else if ctx.get_public_key(from).is_none() {
+      // Requests coming from clients are forwarded to the leader. The ones
+      // forwarded by other validators are not so that they don't bounce
+      // between nodes.
+      trace!("{} forwards request to leader {}", ctx.get_addr(), leader);
+      ctx.send_message(&Message::Request(tx), &leader);
+    }
+  }
+
+  fn handle_tick(&self, ctx: &Context) {
+    // The leader proposes the pending transactions once the interval of the
+    // policy has passed.
+    if ctx.lock_mempool().is_ready() {
+      self.retry_block(ctx);
+    }
+
+    let vc = match self.get_view().lock().unwrap().expire(ctx) {
+      Some(vc) => vc,
+      None => return,
+    };
+
+    info!(
+      "{} timed out at height {} and votes for round {}",
+      ctx.get_addr(),
+      vc.get_height(),
+      vc.get_round()
+    );
+    ctx.broadcast(&Event::ViewChange(vc.clone()));
+    self.process_view_change(ctx, vc);
+  }
+}
@@ -0,0 +1,289 @@
+use crate::codec;
+use crate::Block;
+use log::error;
+use ring::digest;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const CHECKSUM_LEN: usize = digest::SHA256_OUTPUT_LEN;
+
+// A record starts with the length of the encoded block and its checksum.
+const HEADER_LEN: usize = 4 + CHECKSUM_LEN;
+
+// Append-only log of the committed blocks. A record is made of the length of
+// the encoded block as a little endian u32, the SHA-256 of the encoded block
+// and the encoded block itself. A record partially written when the node
+// stopped fails the checksum and is dropped when the log is opened again.
+pub struct Store {
+  file: File,
+}
+
+impl Store {
+  // Opens the log at the given path, or creates it with its directory, and
+  // returns the blocks in the order they have been appended. The log is
+  // truncated after the last valid record.
+  pub fn open(path: &Path) -> io::Result<(Self, Vec<Block>)> {
+    if let Some(dir) = path.parent() {
+      std::fs::create_dir_all(dir)?;
+    }
+
+    let mut file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(path)?;
+
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    let (blocks, offset) = Self::decode_all(&buf);
+
+    if offset < buf.len() {
+      error!(
+        "{} has a corrupted tail of {} bytes which is truncated",
+        path.display(),
+        buf.len() - offset
+      );
+      file.set_len(offset as u64)?;
+      file.sync_all()?;
+    }
+    file.seek(SeekFrom::Start(offset as u64))?;
+
+    Ok((Store { file }, blocks))
+  }
+
+  // Reads the blocks of the log at the given path without changing it. It
+  // also returns the length of the corrupted tail, which is truncated when
+  // the log is opened.
+  pub fn read(path: &Path) -> io::Result<(Vec<Block>, usize)> {
+    let buf = std::fs::read(path)?;
+    let (blocks, offset) = Self::decode_all(&buf);
+    Ok((blocks, buf.len() - offset))
+  }
+
+  // Writes the block at the end of the log and waits for the disk.
+  pub fn append(&mut self, block: &Block) -> io::Result<()> {
+    self.file.write_all(&encode_record(&codec::encode(block)?))?;
+    self.file.sync_data()
+  }
+
+  // Returns the blocks of the valid records and the length they span from the
+  // beginning of the buffer.
+  fn decode_all(buf: &[u8]) -> (Vec<Block>, usize) {
+    let mut blocks = Vec::new();
+    let mut offset = 0;
+    while let Some((payload, len)) = decode_record(&buf[offset..]) {
+      match codec::decode(payload) {
+        Ok(block) => blocks.push(block),
+        Err(_) => break,
+      };
+      offset += len;
+    }
+
+    (blocks, offset)
+  }
+}
+
+// File holding a single value that is replaced as a whole, such as the votes
+// of a node. The value is written as a record to a temporary file which is
+// renamed once it is on disk, so that the file holds either the previous or
+// the new value when the node stops. The value is never sent so its size is
+// not limited to a datagram.
+pub struct StateFile {
+  path: PathBuf,
+}
+
+impl StateFile {
+  // Uses the file at the given path, and creates its directory if needed.
+  pub fn open(path: &Path) -> io::Result<Self> {
+    if let Some(dir) = path.parent() {
+      fs::create_dir_all(dir)?;
+    }
+
+    Ok(StateFile { path: path.to_path_buf() })
+  }
+
+  // Returns the value of the file, or None if it hasn't been written yet. A
+  // corrupted file is an error as the value can't be recovered.
+  pub fn load<T: DeserializeOwned>(&self) -> io::Result<Option<T>> {
+    let buf = match fs::read(&self.path) {
+      Ok(buf) => buf,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e),
+    };
+
+    match decode_record(&buf) {
+      Some((payload, len)) if len == buf.len() => codec::decode_unbounded(payload)
+        .map(Some)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", self.path.display(), e))),
+      _ => Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} is corrupted", self.path.display()),
+      )),
+    }
+  }
+
+  // Replaces the value of the file and waits for the disk.
+  pub fn save<T: Serialize>(&self, value: &T) -> io::Result<()> {
+    let mut tmp = self.path.clone().into_os_string();
+    tmp.push(".tmp");
+
+    let mut file = File::create(&tmp)?;
+    file.write_all(&encode_record(&codec::encode_unbounded(value)))?;
+    file.sync_all()?;
+    fs::rename(&tmp, &self.path)?;
+
+    // The rename is only durable once the directory is on disk as well.
+    match self.path.parent() {
+      Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+      _ => File::open(".")?.sync_all(),
+    }
+  }
+}
+
+fn encode_record(payload: &[u8]) -> Vec<u8> {
+  let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+  record.extend(&(payload.len() as u32).to_le_bytes());
+  record.extend(digest::digest(&digest::SHA256, payload).as_ref());
+  record.extend(payload);
+  record
+}
+
+// Returns the payload of the record at the beginning of the buffer and the
+// length of the record, or None if the record is incomplete or corrupted.
+fn decode_record(buf: &[u8]) -> Option<(&[u8], usize)> {
+  if buf.len() < HEADER_LEN {
+    return None;
+  }
+
+  let len = u32::from_le_bytes(buf[..4].try_into().unwrap()) as usize;
+  let end = HEADER_LEN + len;
+  if buf.len() < end {
+    return None;
+  }
+
+  let payload = &buf[HEADER_LEN..end];
+  if digest::digest(&digest::SHA256, payload).as_ref() != &buf[4..HEADER_LEN] {
+    return None;
+  }
+
+  Some((payload, end))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::crypto::KeyPair;
+  use crate::{Genesis, Seed};
+  use std::net::SocketAddr;
+
+  // Returns an empty directory for the test in the temporary directory.
+  fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rust-ds-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+  }
+
+  fn blocks(n: usize) -> Vec<Block> {
+    let keypair = KeyPair::generate().unwrap();
+    let leader = SocketAddr::from(([127, 0, 0, 1], 3000));
+    let mut blocks = vec![Block::genesis(&Genesis::new("test".to_string(), Vec::new(), Seed::default(), Vec::new()))];
+    for round in 0..n as u64 {
+      let next = blocks.last().unwrap().next(&keypair, leader, round, Vec::new());
+      blocks.push(next);
+    }
+
+    blocks.split_off(1)
+  }
+
+  fn append_all(path: &Path, blocks: &[Block]) {
+    let (mut store, _) = Store::open(path).unwrap();
+    for block in blocks {
+      store.append(block).unwrap();
+    }
+  }
+
+  fn hashes(blocks: &[Block]) -> Vec<String> {
+    blocks.iter().map(|b| b.hash().to_string()).collect()
+  }
+
+  #[test]
+  fn reopens_the_blocks_it_appended() {
+    let path = test_dir("reopen").join("blocks.log");
+    let blocks = blocks(3);
+    append_all(&path, &blocks);
+
+    let (_, read) = Store::open(&path).unwrap();
+    assert_eq!(hashes(&read), hashes(&blocks));
+  }
+
+  #[test]
+  fn truncates_a_partial_record() {
+    let path = test_dir("partial").join("blocks.log");
+    let blocks = blocks(3);
+    append_all(&path, &blocks[..2]);
+
+    // The node stopped in the middle of the second record.
+    let len = fs::metadata(&path).unwrap().len();
+    OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 5).unwrap();
+
+    let (_, read) = Store::open(&path).unwrap();
+    assert_eq!(hashes(&read), hashes(&blocks[..1]));
+    assert_eq!(Store::read(&path).unwrap().1, 0);
+
+    // The next blocks are appended after the last valid record.
+    append_all(&path, &blocks[1..]);
+    let (_, read) = Store::open(&path).unwrap();
+    assert_eq!(hashes(&read), hashes(&blocks));
+  }
+
+  #[test]
+  fn drops_a_corrupted_record() {
+    let path = test_dir("corrupted").join("blocks.log");
+    let blocks = blocks(2);
+    append_all(&path, &blocks);
+
+    let mut buf = fs::read(&path).unwrap();
+    let last = buf.len() - 1;
+    buf[last] ^= 1;
+    fs::write(&path, &buf).unwrap();
+
+    let (blocks_read, corrupted) = Store::read(&path).unwrap();
+    assert_eq!(hashes(&blocks_read), hashes(&blocks[..1]));
+    assert!(corrupted > 0);
+
+    let (_, read) = Store::open(&path).unwrap();
+    assert_eq!(hashes(&read), hashes(&blocks[..1]));
+    assert_eq!(Store::read(&path).unwrap().1, 0);
+  }
+
+  #[test]
+  fn keeps_the_last_state() {
+    let path = test_dir("state").join("votes");
+    let file = StateFile::open(&path).unwrap();
+    assert_eq!(file.load::<(u64, u64)>().unwrap(), None);
+
+    file.save(&(1u64, 2u64)).unwrap();
+    file.save(&(3u64, 4u64)).unwrap();
+
+    let file = StateFile::open(&path).unwrap();
+    assert_eq!(file.load::<(u64, u64)>().unwrap(), Some((3, 4)));
+  }
+
+  #[test]
+  fn rejects_a_corrupted_state() {
+    let path = test_dir("corrupted-state").join("votes");
+    let file = StateFile::open(&path).unwrap();
+    file.save(&(1u64, 2u64)).unwrap();
+
+    let len = fs::metadata(&path).unwrap().len();
+    OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 1).unwrap();
+
+    let file = StateFile::open(&path).unwrap();
+    assert!(file.load::<(u64, u64)>().is_err());
+  }
+}
@@ -0,0 +1,63 @@
+use crate::TxID;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+
+// Number of transactions remembered, after which the oldest are forgotten.
+const MAX_SUBMITTERS: usize = 10_000;
+
+// Addresses of the clients that submitted transactions to this node, which
+// are sent a receipt when the transaction is committed.
+pub struct Submitters {
+  addrs: HashMap<TxID, SocketAddr>,
+  // Transactions from the oldest, including the ones already forgotten.
+  order: VecDeque<TxID>,
+}
+
+impl Submitters {
+  pub fn new() -> Self {
+    Submitters {
+      addrs: HashMap::new(),
+      order: VecDeque::new(),
+    }
+  }
+
+  // Remembers the client of a transaction, unless another one submitted it
+  // first.
+  pub fn add(&mut self, id: TxID, addr: SocketAddr) {
+    if self.addrs.contains_key(&id) {
+      return;
+    }
+    self.addrs.insert(id.clone(), addr);
+    self.order.push_back(id);
+
+    while self.order.len() > MAX_SUBMITTERS {
+      let oldest = self.order.pop_front().unwrap();
+      self.addrs.remove(&oldest);
+    }
+  }
+
+  // Returns the address of the client that submitted the transaction, which
+  // is then forgotten.
+  pub fn take(&mut self, id: &TxID) -> Option<SocketAddr> {
+    self.addrs.remove(id)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::crypto::KeyPair;
+  use crate::Transaction;
+
+  #[test]
+  fn keeps_the_first_submitter() {
+    let id = Transaction::new(1, Vec::new(), &KeyPair::generate().unwrap()).hash();
+    let (first, second) = (SocketAddr::from(([10, 0, 0, 1], 4000)), SocketAddr::from(([10, 0, 0, 2], 4000)));
+
+    let mut submitters = Submitters::new();
+    submitters.add(id.clone(), first);
+    submitters.add(id.clone(), second);
+    assert_eq!(submitters.take(&id), Some(first));
+    assert_eq!(submitters.take(&id), None);
+  }
+}
@@ -0,0 +1,13 @@
+use super::BlockID;
+use serde::{Deserialize, Serialize};
+
+// Request of a node for the blocks it misses. The answer is a range of blocks
+// ordered by height, which can be shorter than asked to fit in a datagram.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum BlockRequest {
+  // Blocks of the best branch starting at the given height.
+  Height(u64),
+  // The given block preceded by its ancestors, when the node is on another
+  // branch and doesn't know the parent of a block.
+  Ancestors(BlockID),
+}
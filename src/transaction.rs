@@ -0,0 +1,140 @@
+use super::codec;
+use super::crypto::{KeyPair, PublicKey, Signature};
+use super::merkle::MerkleProof;
+use super::BlockID;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+
+const ID_SHORT_LEN: usize = 4;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TxID([u8; 32]);
+
+impl std::fmt::Display for TxID {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    for i in 0..ID_SHORT_LEN {
+      write!(f, "{:02x?}", self.0[i])?;
+    }
+    Ok(())
+  }
+}
+
+// Entry of a block submitted by a client. The nonce of a sender must grow
+// with each of its transactions in the chain so that a transaction can't be
+// replayed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Transaction {
+  sender: PublicKey,
+  nonce: u64,
+  payload: Vec<u8>,
+  signature: Signature,
+}
+
+impl Transaction {
+  pub fn new(nonce: u64, payload: Vec<u8>, keypair: &KeyPair) -> Self {
+    let sender = keypair.public_key();
+    let signature = keypair.sign(&Self::message(&sender, nonce, &payload));
+
+    Transaction {
+      sender,
+      nonce,
+      payload,
+      signature,
+    }
+  }
+
+  pub fn get_sender(&self) -> &PublicKey {
+    &self.sender
+  }
+
+  pub fn get_nonce(&self) -> u64 {
+    self.nonce
+  }
+
+  pub fn get_payload(&self) -> &[u8] {
+    &self.payload
+  }
+
+  // Checks that the transaction has been signed by its sender.
+  pub fn verify(&self) -> bool {
+    let msg = Self::message(&self.sender, self.nonce, &self.payload);
+    self.sender.verify(&msg, &self.signature)
+  }
+
+  // The signature is left out as it is computed over the same content.
+  pub fn hash(&self) -> TxID {
+    let buf = Self::message(&self.sender, self.nonce, &self.payload);
+    let d = digest::digest(&digest::SHA256, &buf);
+
+    let mut id: [u8; digest::SHA256_OUTPUT_LEN] = Default::default();
+    id[..].clone_from_slice(d.as_ref());
+
+    TxID(id)
+  }
+
+  fn message(sender: &PublicKey, nonce: u64, payload: &[u8]) -> Vec<u8> {
+    codec::encode_unbounded(&("transaction", sender, nonce, payload))
+  }
+}
+
+// Notice sent to the client that submitted a transaction once it has been
+// committed. The proof of inclusion can be checked against the header of the
+// block.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Receipt {
+  tx: TxID,
+  block: BlockID,
+  height: u64,
+  proof: MerkleProof,
+}
+
+impl Receipt {
+  pub fn new(tx: TxID, block: BlockID, height: u64, proof: MerkleProof) -> Self {
+    Receipt {
+      tx,
+      block,
+      height,
+      proof,
+    }
+  }
+
+  pub fn get_tx(&self) -> &TxID {
+    &self.tx
+  }
+
+  pub fn get_block(&self) -> &BlockID {
+    &self.block
+  }
+
+  pub fn get_height(&self) -> u64 {
+    self.height
+  }
+
+  pub fn get_proof(&self) -> &MerkleProof {
+    &self.proof
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rejects_a_tampered_transaction() {
+    let keypair = KeyPair::generate().unwrap();
+    let tx = Transaction::new(1, vec![1, 2, 3], &keypair);
+    assert!(tx.verify());
+
+    let mut other = tx.clone();
+    other.payload = vec![1, 2, 4];
+    assert!(!other.verify());
+
+    let mut other = tx.clone();
+    other.nonce = 2;
+    assert!(!other.verify());
+
+    let mut other = tx;
+    other.sender = KeyPair::generate().unwrap().public_key();
+    assert!(!other.verify());
+  }
+}
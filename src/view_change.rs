@@ -0,0 +1,51 @@
+use super::codec;
+use super::crypto::{KeyPair, Signature};
+use super::Peer;
+use serde::{Deserialize, Serialize};
+
+// Vote of a validator to move the given height to a new round, thus skipping
+// the leader of the previous round.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ViewChange {
+  height: u64,
+  round: u64,
+  index: usize,
+  signature: Signature,
+}
+
+impl ViewChange {
+  pub fn new(height: u64, round: u64, index: usize, keypair: &KeyPair) -> Self {
+    ViewChange {
+      height,
+      round,
+      index,
+      signature: keypair.sign(&Self::message(height, round)),
+    }
+  }
+
+  pub fn get_height(&self) -> u64 {
+    self.height
+  }
+
+  pub fn get_round(&self) -> u64 {
+    self.round
+  }
+
+  pub fn get_index(&self) -> usize {
+    self.index
+  }
+
+  pub fn verify(&self, peers: &[Peer]) -> bool {
+    match peers.get(self.index) {
+      Some(peer) => {
+        let msg = Self::message(self.height, self.round);
+        peer.get_public_key().verify(&msg, &self.signature)
+      }
+      None => false,
+    }
+  }
+
+  fn message(height: u64, round: u64) -> Vec<u8> {
+    codec::encode_unbounded(&("view-change", height, round))
+  }
+}
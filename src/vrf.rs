@@ -0,0 +1,191 @@
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::scalar::{self, Scalar};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+
+// Verifiable random function ECVRF-EDWARDS25519-SHA512-TAI of RFC 9381 over
+// the Ed25519 keys. Unlike a signature, there is a single valid proof for a
+// key and a message, so the output can't be chosen by the owner of the key.
+
+const SUITE: u8 = 0x03;
+const CHALLENGE_LEN: usize = 16;
+
+// Gamma point, challenge and response of a proof.
+pub const PROOF_LEN: usize = 32 + CHALLENGE_LEN + 32;
+
+pub type Output = [u8; 64];
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Proof(Vec<u8>);
+
+fn sha512(parts: &[&[u8]]) -> [u8; 64] {
+  let mut ctx = digest::Context::new(&digest::SHA512);
+  for part in parts {
+    ctx.update(part);
+  }
+  ctx.finish().as_ref().try_into().unwrap()
+}
+
+// Decodes a point, which must be in its canonical encoding so that a proof
+// has a single encoding as well.
+fn decode_point(bytes: &[u8]) -> Option<EdwardsPoint> {
+  let compressed = CompressedEdwardsY(bytes.try_into().ok()?);
+  let point = compressed.decompress()?;
+  if point.compress() != compressed {
+    return None;
+  }
+  Some(point)
+}
+
+// Maps the message to a point of the prime order subgroup by hashing it with
+// a counter until the hash is a valid point.
+fn encode_to_curve(public_key: &[u8; 32], alpha: &[u8]) -> EdwardsPoint {
+  (0..=u8::MAX)
+    .find_map(|ctr| {
+      let hash = sha512(&[&[SUITE, 0x01], public_key, alpha, &[ctr, 0x00]]);
+      CompressedEdwardsY(hash[..32].try_into().unwrap()).decompress()
+    })
+    .expect("no counter gives a point")
+    .mul_by_cofactor()
+}
+
+fn challenge(points: &[&EdwardsPoint]) -> Scalar {
+  let mut ctx = digest::Context::new(&digest::SHA512);
+  ctx.update(&[SUITE, 0x02]);
+  for point in points {
+    ctx.update(point.compress().as_bytes());
+  }
+  ctx.update(&[0x00]);
+
+  let mut c = [0u8; 32];
+  c[..CHALLENGE_LEN].copy_from_slice(&ctx.finish().as_ref()[..CHALLENGE_LEN]);
+  Scalar::from_bytes_mod_order(c)
+}
+
+fn output(gamma: &EdwardsPoint) -> Output {
+  sha512(&[&[SUITE, 0x03], gamma.mul_by_cofactor().compress().as_bytes(), &[0x00]])
+}
+
+// Returns the proof for the message with the secret key given by the seed of
+// an Ed25519 key.
+pub fn prove(seed: &[u8; 32], alpha: &[u8]) -> Proof {
+  let hashed = sha512(&[seed]);
+  let x = Scalar::from_bytes_mod_order(scalar::clamp_integer(hashed[..32].try_into().unwrap()));
+  let y = EdwardsPoint::mul_base(&x);
+
+  let h = encode_to_curve(&y.compress().to_bytes(), alpha);
+  let gamma = h * x;
+  let k = Scalar::from_bytes_mod_order_wide(&sha512(&[&hashed[32..], h.compress().as_bytes()]));
+  let c = challenge(&[&y, &h, &gamma, &EdwardsPoint::mul_base(&k), &(h * k)]);
+  let s = k + c * x;
+
+  let mut proof = Vec::with_capacity(PROOF_LEN);
+  proof.extend(gamma.compress().as_bytes());
+  proof.extend(&c.as_bytes()[..CHALLENGE_LEN]);
+  proof.extend(s.as_bytes());
+  Proof(proof)
+}
+
+// Returns the output of the proof if it is the one of the key for the
+// message.
+pub fn verify(public_key: &[u8; 32], alpha: &[u8], proof: &Proof) -> Option<Output> {
+  let y = decode_point(public_key)?;
+  if y.is_small_order() || proof.0.len() != PROOF_LEN {
+    return None;
+  }
+
+  let gamma = decode_point(&proof.0[..32])?;
+  let mut c = [0u8; 32];
+  c[..CHALLENGE_LEN].copy_from_slice(&proof.0[32..32 + CHALLENGE_LEN]);
+  let c = Scalar::from_bytes_mod_order(c);
+  let s = Option::<Scalar>::from(Scalar::from_canonical_bytes(proof.0[32 + CHALLENGE_LEN..].try_into().unwrap()))?;
+
+  let h = encode_to_curve(public_key, alpha);
+  let u = EdwardsPoint::vartime_double_scalar_mul_basepoint(&-c, &y, &s);
+  let v = h * s - gamma * c;
+  if challenge(&[&y, &h, &gamma, &u, &v]) != c {
+    return None;
+  }
+
+  Some(output(&gamma))
+}
+
+// Returns the output of a proof without checking it, for the proofs made by
+// this node.
+pub fn proof_to_hash(proof: &Proof) -> Option<Output> {
+  decode_point(proof.0.get(..32)?).map(|gamma| output(&gamma))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+  }
+
+  fn unhex(s: &str) -> Vec<u8> {
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+  }
+
+  // Example 16 of RFC 9381 for the suite.
+  const SECRET_KEY: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+  const PUBLIC_KEY: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+  const PROOF: &str = concat!(
+    "8657106690b5526245a92b003bb079ccd1a92130477671f6fc01ad16f26f723f",
+    "26f8a57ccaed74ee1b190bed1f479d97",
+    "27d2d0f9b005a6e456a35d4fb0daab1268a1b0db10836d9826a528ca76567805",
+  );
+  const OUTPUT: &str = concat!(
+    "90cf1df3b703cce59e2a35b925d411164068269d7b2d29f3301c03dd757876ff",
+    "66b71dda49d2de59d03450451af026798e8f81cd2e333de5cdf4f3e140fdd8ae",
+  );
+
+  fn keys() -> ([u8; 32], [u8; 32]) {
+    (unhex(SECRET_KEY).try_into().unwrap(), unhex(PUBLIC_KEY).try_into().unwrap())
+  }
+
+  #[test]
+  fn matches_the_example() {
+    let (seed, public_key) = keys();
+    let proof = prove(&seed, b"");
+
+    assert_eq!(hex(&proof.0), PROOF);
+    assert_eq!(verify(&public_key, b"", &proof).map(|o| hex(&o)), Some(OUTPUT.to_string()));
+    assert_eq!(proof_to_hash(&proof).map(|o| hex(&o)), Some(OUTPUT.to_string()));
+  }
+
+  #[test]
+  fn proves_with_the_keys_of_the_nodes() {
+    let keypair = crate::crypto::KeyPair::generate().unwrap();
+    let proof = keypair.prove(b"message");
+
+    assert_eq!(proof, keypair.prove(b"message"));
+    assert_eq!(keypair.public_key().verify_proof(b"message", &proof), proof_to_hash(&proof));
+    assert!(keypair.public_key().verify_proof(b"message", &proof).is_some());
+  }
+
+  #[test]
+  fn rejects_invalid_proofs() {
+    let (seed, public_key) = keys();
+    let proof = prove(&seed, b"message");
+    assert!(verify(&public_key, b"message", &proof).is_some());
+    assert!(verify(&public_key, b"other message", &proof).is_none());
+
+    let other = crate::crypto::KeyPair::generate().unwrap().public_key();
+    assert!(verify(&unhex(&other.to_string()).try_into().unwrap(), b"message", &proof).is_none());
+
+    for i in 0..PROOF_LEN {
+      let mut tampered = proof.clone();
+      tampered.0[i] ^= 1;
+      assert!(verify(&public_key, b"message", &tampered).is_none(), "byte {}", i);
+    }
+    assert!(verify(&public_key, b"message", &Proof(proof.0[1..].to_vec())).is_none());
+
+    // The identity is a key of small order which would accept any proof.
+    let mut identity = [0u8; 32];
+    identity[0] = 1;
+    assert!(verify(&identity, b"message", &proof).is_none());
+  }
+}